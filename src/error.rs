@@ -0,0 +1,184 @@
+use std::{fmt, time::Duration};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TwoFactorMethod {
+    Totp,
+    Email,
+    Password,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    AuthFailed(String),
+    /// Login was refused until a second factor is supplied with `Credentials::TwoFactor`.
+    TwoFactorRequired(TwoFactorMethod),
+    RateLimited { retry_after: Option<Duration> },
+    NotFound(String),
+    PermissionDenied(String),
+    /// Any other error reported by the server, with its Rocket.Chat error code.
+    Method { code: String, message: String },
+    /// The server sent something we could not make sense of.
+    Decode { error: serde_json::Error, raw: Value },
+    /// Request parameters could not be serialized.
+    Encode(serde_json::Error),
+    Protocol(String),
+    Http(reqwest::Error),
+    /// The websocket could not be opened, or was closed under our feet.
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+
+    /// Classifies an error returned by a DDP method call. siderite only hands those
+    /// out as text, so the Rocket.Chat code is recovered from the formatted message.
+    pub(crate) fn method(e: impl fmt::Display) -> Self {
+        let message = e.to_string();
+        let json = serde_json::from_str::<Value>(&message).unwrap_or(Value::Null);
+        let code = error_code(&message, &json);
+        Self::server(code, message, &json["details"])
+    }
+
+    /// siderite reports errors with anyhow, which we keep out of our API.
+    pub(crate) fn transport(e: anyhow::Error) -> Self {
+        Error::Transport(e.into())
+    }
+
+    /// Classifies an error response from the REST API.
+    pub(crate) fn status(status: reqwest::StatusCode, body: &Value) -> Self {
+        let message = body["error"].as_str()
+            .or(body["message"].as_str())
+            .unwrap_or_else(|| status.canonical_reason().unwrap_or(""))
+            .to_string();
+
+        match status.as_u16() {
+            401 => Error::AuthFailed(message),
+            403 => Error::PermissionDenied(message),
+            404 => Error::NotFound(message),
+            429 => Error::RateLimited { retry_after: None },
+            _ => {
+                let code = body["errorType"].as_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| error_code(&message, &Value::Null));
+                Self::server(code, message, &body["details"])
+            }
+        }
+    }
+
+    fn server(code: String, message: String, details: &Value) -> Self {
+        match code.as_str() {
+            "totp-required" => {
+                // The reason is "TOTP Required" whatever the method, only details tell
+                let method = TwoFactorMethod::deserialize(&details["method"]).unwrap_or_else(|_| {
+                    if message.contains("email") { TwoFactorMethod::Email } else { TwoFactorMethod::Totp }
+                });
+                Error::TwoFactorRequired(method)
+            },
+            "error-too-many-requests" => Error::RateLimited { retry_after: wait_time(&message) },
+            "error-not-allowed" | "error-action-not-allowed" | "not-authorized" | "unauthorized" => {
+                Error::PermissionDenied(message)
+            },
+            "error-invalid-room" | "error-room-not-found" | "error-invalid-user" | "error-invalid-message" | "404" => {
+                Error::NotFound(message)
+            },
+            _ => Error::Method { code, message },
+        }
+    }
+
+}
+
+/// Meteor formats its errors as `reason [code]`, or as JSON when `json` is not null.
+fn error_code(message: &str, json: &Value) -> String {
+    match &json["error"] {
+        Value::String(code) => return code.clone(),
+        Value::Number(code) => return code.to_string(),
+        _ => {},
+    }
+
+    message.rsplit_once('[')
+        .and_then(|(_, code)| code.strip_suffix(']'))
+        .unwrap_or("")
+        .to_string()
+}
+
+/// The DDP rate limiter asks to "wait N seconds before trying again".
+fn wait_time(message: &str) -> Option<Duration> {
+    let (_, rest) = message.split_once("wait ")?;
+    let secs = rest.split_whitespace().next()?.parse().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+pub(crate) fn decode<T: DeserializeOwned>(raw: Value) -> Result<T> {
+    T::deserialize(&raw).map_err(|error| Error::Decode { error, raw })
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AuthFailed(msg) => write!(f, "Login failed: {}", msg),
+            Error::TwoFactorRequired(method) => write!(f, "Two-factor authentication required ({:?})", method),
+            Error::RateLimited { retry_after: Some(d) } => write!(f, "Rate limited, retry in {:?}", d),
+            Error::RateLimited { retry_after: None } => write!(f, "Rate limited"),
+            Error::NotFound(msg) => write!(f, "Not found: {}", msg),
+            Error::PermissionDenied(msg) => write!(f, "Permission denied: {}", msg),
+            Error::Method { code, message } => write!(f, "Server error {}: {}", code, message),
+            Error::Decode { error, raw } => write!(f, "Could not decode {}: {}", raw, error),
+            Error::Encode(e) => write!(f, "Could not encode request: {}", e),
+            Error::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            Error::Http(e) => write!(f, "HTTP error: {}", e),
+            Error::Transport(e) => write!(f, "Connection error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode { error, .. } | Error::Encode(error) => Some(error),
+            Error::Http(e) => Some(e),
+            Error::Transport(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+
+/// Only serialization errors convert implicitly; decoding goes through `decode`,
+/// which keeps the offending JSON around.
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Encode(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn classify_method_errors() {
+        assert!(matches!(Error::method("TOTP Required [totp-required]"),
+                         Error::TwoFactorRequired(TwoFactorMethod::Totp)));
+        assert!(matches!(Error::method("Error, too many requests. Please slow down. You must wait 7 seconds before trying again. [error-too-many-requests]"),
+                         Error::RateLimited { retry_after: Some(d) } if d == Duration::from_secs(7)));
+        assert!(matches!(Error::method(r#"{"error":"totp-required","reason":"TOTP Required","details":{"method":"email"}}"#),
+                         Error::TwoFactorRequired(TwoFactorMethod::Email)));
+        assert!(matches!(Error::method("Invalid room [error-invalid-room]"), Error::NotFound(_)));
+        match Error::method("Something odd [error-odd]") {
+            Error::Method { code, .. } => assert_eq!(code, "error-odd"),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+}
@@ -0,0 +1,366 @@
+use serde::Deserialize;
+use serde_json::{self, Value};
+use std::collections::{HashMap, VecDeque};
+use log::debug;
+
+use crate::{Error, Result, ServerMessage, error::decode};
+use crate::schema::{MessageID, Presence, Reactions, Room, RoomEventData, RoomExtraInfo, Status, Subscription, Timestamp, UserID};
+
+/// What happened to a document pushed through one of the `*-changed` streams.
+#[derive(Debug)]
+pub enum Change<T> {
+    Inserted(T),
+    Updated(T),
+    Removed(String),
+}
+
+/// A user's presence or status message changed, from the `user-status` stream.
+#[derive(Clone, Debug)]
+pub struct PresenceChanged {
+    pub user: UserID,
+    pub username: String,
+    pub presence: Presence,
+    pub text: Option<String>,
+}
+
+impl PresenceChanged {
+    pub fn status(&self) -> Status {
+        Status::new(self.presence, self.text.clone())
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Message(RoomEventData, Option<RoomExtraInfo>),
+    MessageEdited(RoomEventData),
+    /// A message we already know was sent again without being edited, e.g. because
+    /// a reply updated its thread counters or its URL previews were filled in.
+    MessageUpdated(RoomEventData),
+    MessageDeleted { room: String, id: MessageID },
+    /// Someone reacted to a message, or withdrew a reaction. `added` and `removed`
+    /// hold (emoji, username) pairs.
+    ReactionsChanged { message: RoomEventData, added: Vec<(String, String)>, removed: Vec<(String, String)> },
+    PresenceChanged(PresenceChanged),
+    RoomChanged(Change<Room>),
+    SubscriptionChanged(Change<Subscription>),
+    Typing { room: String, username: String, typing: bool },
+    /// The connection was lost and re-established; events may have been missed.
+    Reconnected,
+    /// Anything we do not know how to decode, passed through untouched.
+    Other(ServerMessage),
+}
+
+#[derive(Deserialize)]
+struct StreamFields {
+    #[serde(rename = "eventName")]
+    event: String,
+    #[serde(default)]
+    args: Vec<Value>,
+}
+
+impl From<ServerMessage> for Event {
+    fn from(msg: ServerMessage) -> Self {
+        match decode_message(&msg) {
+            Ok(Some(event)) => event,
+            Ok(None) => Event::Other(msg),
+            Err(e) => {
+                debug!("Could not decode {}: {}", msg.pretty(), e);
+                Event::Other(msg)
+            }
+        }
+    }
+}
+
+fn decode_message(msg: &ServerMessage) -> Result<Option<Event>> {
+    let raw = serde_json::to_value(msg)?;
+    if raw["msg"] != "changed" {
+        return Ok(None)
+    }
+
+    let collection = match raw["collection"].as_str() {
+        Some(c) => c.to_string(),
+        None => return Ok(None),
+    };
+
+    let fields: StreamFields = decode(raw["fields"].clone())?;
+    decode_stream(&collection, &fields.event, fields.args)
+}
+
+pub(crate) fn decode_stream(collection: &str, event: &str, args: Vec<Value>) -> Result<Option<Event>> {
+    let mut args = args.into_iter();
+
+    let event = match collection {
+        "stream-room-messages" => {
+            let data: RoomEventData = decode(next(&mut args)?)?;
+            let info = args.next().map(decode).transpose()?;
+            let rewritten = data.updated_at.map_or(false, |updated| written_after(updated, data.ts))
+                || data.tcount.is_some() || data.tlm.is_some();
+            // An edit is the last write to the message, anything written since then
+            // (reactions, thread replies...) is only an update of a message edited earlier
+            let edited = match (data.edited_at, data.updated_at) {
+                (Some(edited), Some(updated)) => !written_after(updated, edited),
+                (edited, None) => edited.is_some(),
+                (None, _) => false,
+            };
+            if edited {
+                Event::MessageEdited(data)
+            } else if rewritten {
+                Event::MessageUpdated(data)
+            } else {
+                Event::Message(data, info)
+            }
+        },
+        "stream-notify-room" => {
+            let (room, name) = match event.rsplit_once('/') {
+                Some(split) => split,
+                None => return Ok(None),
+            };
+            match name {
+                "deleteMessage" => {
+                    #[derive(Deserialize)]
+                    struct Deleted { #[serde(rename = "_id")] id: MessageID }
+                    let Deleted { id } = decode(next(&mut args)?)?;
+                    Event::MessageDeleted { room: room.to_string(), id }
+                },
+                "typing" => {
+                    let username = decode(next(&mut args)?)?;
+                    let typing = decode(next(&mut args)?)?;
+                    Event::Typing { room: room.to_string(), username, typing }
+                },
+                _ => return Ok(None),
+            }
+        },
+        "stream-notify-logged" if event == "user-status" => {
+            let mut fields = match next(&mut args)? {
+                Value::Array(a) => a,
+                other => return Err(Error::Protocol(format!("unexpected user-status payload {}", other))),
+            };
+            let message = fields.get_mut(3).map(Value::take).map(decode).transpose()?;
+            fields.truncate(3);
+            let (user, username, code): (UserID, String, u64) = decode(Value::Array(fields))?;
+            let presence = Presence::from_code(code)
+                .ok_or_else(|| Error::Protocol(format!("unknown status code {}", code)))?;
+            let text = Status::new(presence, message).message;
+            Event::PresenceChanged(PresenceChanged { user, username, presence, text })
+        },
+        "stream-notify-user" => {
+            match event.rsplit_once('/').map(|(_, name)| name) {
+                Some("rooms-changed") => Event::RoomChanged(change(&mut args)?),
+                Some("subscriptions-changed") => Event::SubscriptionChanged(change(&mut args)?),
+                _ => return Ok(None),
+            }
+        },
+        _ => return Ok(None),
+    };
+
+    Ok(Some(event))
+}
+
+/// `_updatedAt` is stamped separately from `ts` and `editedAt` when the server
+/// writes a message, so it may lag behind them by a few milliseconds.
+const WRITE_SLACK_MS: i64 = 1000;
+
+fn written_after(updated: Timestamp, t: Timestamp) -> bool {
+    updated.as_millis() - t.as_millis() > WRITE_SLACK_MS
+}
+
+fn next(args: &mut impl Iterator<Item=Value>) -> Result<Value> {
+    args.next().ok_or_else(|| Error::Protocol("missing stream argument".into()))
+}
+
+fn change<T: serde::de::DeserializeOwned>(args: &mut impl Iterator<Item=Value>) -> Result<Change<T>> {
+    let action = next(args)?;
+    let payload = next(args)?;
+    Ok(match action.as_str() {
+        Some("inserted") => Change::Inserted(decode(payload)?),
+        Some("updated") => Change::Updated(decode(payload)?),
+        Some("removed") => {
+            let id = payload["_id"].as_str()
+                .ok_or_else(|| Error::Protocol("removed document without _id".into()))?;
+            Change::Removed(id.to_string())
+        },
+        _ => return Err(Error::Protocol(format!("unknown change type {}", action))),
+    })
+}
+
+const TRACKED_MESSAGES: usize = 1024;
+
+/// Remembers the edit time and reactions of recently seen messages, so that the
+/// server re-broadcasting a message can be told apart as a new reaction.
+#[derive(Debug, Default)]
+pub(crate) struct ReactionTracker {
+    seen: HashMap<MessageID, (Option<Timestamp>, Reactions)>,
+    order: VecDeque<MessageID>,
+}
+
+impl ReactionTracker {
+
+    pub fn apply(&mut self, event: Event) -> Event {
+        let (data, edited) = match event {
+            Event::Message(data, info) => {
+                if self.seen.contains_key(&data.id) || !data.reactions.is_empty() {
+                    (data, false)
+                } else {
+                    self.remember(&data);
+                    return Event::Message(data, info)
+                }
+            },
+            Event::MessageUpdated(data) => (data, false),
+            Event::MessageEdited(data) => (data, true),
+            Event::MessageDeleted { room, id } => {
+                self.seen.remove(&id);
+                return Event::MessageDeleted { room, id }
+            },
+            other => return other,
+        };
+
+        let previous = self.remember(&data);
+        match previous {
+            Some((edited_at, reactions)) if edited_at == data.edited_at => {
+                if reactions == data.reactions {
+                    Event::MessageUpdated(data)
+                } else {
+                    let added = data.reactions.difference(&reactions);
+                    let removed = reactions.difference(&data.reactions);
+                    Event::ReactionsChanged { message: data, added, removed }
+                }
+            },
+            None if !edited && !data.reactions.is_empty() => {
+                let added = data.reactions.difference(&Reactions::default());
+                Event::ReactionsChanged { message: data, added, removed: vec![] }
+            },
+            _ if edited => Event::MessageEdited(data),
+            _ => Event::MessageUpdated(data),
+        }
+    }
+
+    fn remember(&mut self, data: &RoomEventData) -> Option<(Option<Timestamp>, Reactions)> {
+        let previous = self.seen.insert(data.id.clone(), (data.edited_at, data.reactions.clone()));
+        if previous.is_none() {
+            self.order.push_back(data.id.clone());
+            while self.order.len() > TRACKED_MESSAGES {
+                if let Some(old) = self.order.pop_front() {
+                    self.seen.remove(&old);
+                }
+            }
+        }
+        previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn decode_typing() {
+        let event = decode_stream("stream-notify-room", "fjGcXmddo5h8sp85n/typing", vec![json!("syn"), json!(true)])
+            .unwrap().unwrap();
+        match event {
+            Event::Typing { room, username, typing } => {
+                assert_eq!(room, "fjGcXmddo5h8sp85n");
+                assert_eq!(username, "syn");
+                assert!(typing);
+            },
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_user_status() {
+        let event = decode_stream("stream-notify-logged", "user-status",
+                                  vec![json!(["hza29JX8SbnwqJwwh", "syn", 2, "lunch"])])
+            .unwrap().unwrap();
+        match event {
+            Event::PresenceChanged(PresenceChanged { user, username, presence, text }) => {
+                assert_eq!(&user, "hza29JX8SbnwqJwwh");
+                assert_eq!(username, "syn");
+                assert_eq!(presence, Presence::Away);
+                assert_eq!(text.as_deref(), Some("lunch"));
+            },
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    fn message(reactions: Value) -> Event {
+        broadcast(json!({ "reactions": reactions }))
+    }
+
+    fn broadcast(fields: Value) -> Event {
+        let mut data = json!({
+            "_id": "BFa2866ehEnpHCmsc",
+            "msg": "tralala pouet",
+            "rid": "fjGcXmddo5h8sp85n",
+            "ts": { "$date": 1618995166553u64 },
+            "u": { "_id": "hza29JX8SbnwqJwwh", "name": "syn", "username": "syn" },
+        });
+        for (k, v) in fields.as_object().unwrap() {
+            data[k] = v.clone();
+        }
+        decode_stream("stream-room-messages", "fjGcXmddo5h8sp85n", vec![data]).unwrap().unwrap()
+    }
+
+    #[test]
+    fn track_reactions() {
+        let mut tracker = ReactionTracker::default();
+
+        assert!(matches!(tracker.apply(message(json!({}))), Event::Message(..)));
+
+        match tracker.apply(message(json!({":tada:": {"usernames": ["qwerty"]}}))) {
+            Event::ReactionsChanged { added, removed, .. } => {
+                assert_eq!(added, vec![(":tada:".to_string(), "qwerty".to_string())]);
+                assert!(removed.is_empty());
+            },
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        match tracker.apply(message(json!({}))) {
+            Event::ReactionsChanged { added, removed, .. } => {
+                assert!(added.is_empty());
+                assert_eq!(removed, vec![(":tada:".to_string(), "qwerty".to_string())]);
+            },
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rebroadcast_is_not_a_new_message() {
+        let mut tracker = ReactionTracker::default();
+
+        assert!(matches!(tracker.apply(message(json!({}))), Event::Message(..)));
+        assert!(matches!(tracker.apply(message(json!({}))), Event::MessageUpdated(_)));
+    }
+
+    #[test]
+    fn untracked_rebroadcast_is_not_a_new_message() {
+        let mut tracker = ReactionTracker::default();
+
+        // A reply in the thread of a message from before we connected
+        let event = broadcast(json!({ "tcount": 3, "tlm": { "$date": 1618999999999u64 },
+                                      "_updatedAt": { "$date": 1618999999999u64 } }));
+        assert!(matches!(tracker.apply(event), Event::MessageUpdated(_)));
+
+        // URL previews filled in
+        let event = broadcast(json!({ "_id": "xqVjAp9Kz2Tgeixkh", "_updatedAt": { "$date": 1618995170000u64 } }));
+        assert!(matches!(tracker.apply(event), Event::MessageUpdated(_)));
+    }
+
+    #[test]
+    fn old_edit_is_not_reported_again() {
+        let edit = broadcast(json!({ "editedAt": { "$date": 1618995200000u64 },
+                                     "_updatedAt": { "$date": 1618995200003u64 } }));
+        assert!(matches!(edit, Event::MessageEdited(_)));
+
+        let reacted = broadcast(json!({ "editedAt": { "$date": 1618995200000u64 },
+                                        "_updatedAt": { "$date": 1618999999999u64 } }));
+        assert!(matches!(reacted, Event::MessageUpdated(_)));
+    }
+
+    #[test]
+    fn unknown_stream_is_not_decoded() {
+        assert!(decode_stream("stream-whatever", "x", vec![]).unwrap().is_none());
+    }
+
+}
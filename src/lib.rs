@@ -1,22 +1,42 @@
-use anyhow::{Result, anyhow};
 use ring::digest::{Digest, SHA256, digest};
-use schema::{LoginReply, MessageID, Presence, Room, ShortUser, Spotlight};
+use schema::{History, LoginReply, MessageID, MessageSync, Presence, Room, RoomEventData, ShortUser, Spotlight, Status, Subscription, UserID};
 use siderite::{Connection, connection::MethodResult};
 use serde_json::{self, json, Value};
 use futures::Stream;
-use log::{debug};
+use log::{debug, warn};
+use std::{sync::{Arc, Mutex}, time::Duration};
 pub use siderite::protocol::ServerMessage;
+pub use event::Event;
+pub use error::{Error, Result, TwoFactorMethod};
+pub use timestamp::Timestamp;
+use error::decode;
 
+pub mod error;
 pub mod schema;
 pub mod session;
 pub mod rest;
+pub mod event;
+pub mod markdown;
+pub mod timestamp;
 
 #[derive(Debug)]
 pub enum Credentials {
     Clear { user: String, password: String },
     Token(String),
+    /// Personal access token, as generated in the account preferences.
+    PersonalToken { user_id: String, token: String },
+    /// Second factor (TOTP or emailed code) completing another login method.
+    TwoFactor { login: Box<Credentials>, code: String },
+    Ldap { user: String, password: String },
+    /// Access token obtained from an OAuth provider configured on the server (e.g. `google`).
+    OAuth { service: String, access_token: String, expires_in: Option<u64> },
+    /// Credential token handed out by the server at the end of a CAS login.
+    Cas { credential_token: String },
+    /// Credential token handed out by the server at the end of a SAML login.
+    Saml { credential_token: String },
 }
 
+
 impl From<String> for Credentials {
     fn from(creds: String) -> Self {
         let mut split = creds.splitn(2, ":");
@@ -31,6 +51,9 @@ impl From<String> for Credentials {
     }
 }
 
+/// Lifetime assumed for OAuth access tokens when the provider did not say.
+const OAUTH_EXPIRES_IN: u64 = 3600;
+
 trait HexDigest {
     fn hexdigest(&self) -> String;
 }
@@ -41,44 +64,84 @@ impl HexDigest for Digest {
         let v = self.as_ref();
         let mut s = String::with_capacity(2 * v.len());
         for byte in v {
-            write!(s, "{:0x}", *byte).unwrap();
+            write!(s, "{:02x}", *byte).unwrap();
         }
         s 
     }
 }
 
 impl Credentials {
-    fn json(self) -> Value {
+    fn json(&self) -> Value {
         match self {
             Self::Clear { user, password } => {
                 let digest = digest(&SHA256, password.as_bytes())
                     .hexdigest();
+                let user = if user.contains('@') {
+                    json!({"email": user})
+                } else {
+                    json!({"username": user})
+                };
                 json!({
-                    "user": {"username": user},
-                    "password: ": {
+                    "user": user,
+                    "password": {
                         "algorithm": "sha-256",
                         "digest": digest
                     }
                 })
             },
-            Self::Token(tok) => {
+            Self::Token(tok) | Self::PersonalToken { token: tok, .. } => {
                 json!({"resume": tok})
-            }
+            },
+            Self::TwoFactor { login, code } => {
+                json!({"totp": {"login": login.json(), "code": code}})
+            },
+            Self::Ldap { user, password } => {
+                json!({"ldap": true, "username": user, "ldapPass": password, "ldapOptions": {}})
+            },
+            Self::OAuth { service, access_token, expires_in } => {
+                // The server insists on an integer here
+                let expires_in = expires_in.unwrap_or(OAUTH_EXPIRES_IN);
+                json!({"oauth": {"serviceName": service, "accessToken": access_token, "expiresIn": expires_in}})
+            },
+            Self::Cas { credential_token } => {
+                json!({"cas": {"credentialToken": credential_token}})
+            },
+            Self::Saml { credential_token } => {
+                json!({"saml": true, "credentialToken": credential_token})
+            },
         }
     }
 }
 
+fn login_error(e: impl std::fmt::Display) -> Error {
+    match Error::method(e) {
+        Error::Method { message, .. } => Error::AuthFailed(message),
+        other => other,
+    }
+}
+
 
 pub struct Rasta {
+    ws_url: String,
     connection: Connection,
+    handle: Arc<Mutex<siderite::connection::Handle>>,
     rest: rest::Client,
+    resume: Option<String>,
+    user: Option<UserID>,
+    username: Arc<Mutex<Option<String>>>,
+    subscriptions: Vec<(String, String, Vec<Value>)>,
+    reactions: event::ReactionTracker,
 }
 
 pub struct Handle {
-    handle: siderite::connection::Handle,
+    handle: Arc<Mutex<siderite::connection::Handle>>,
     rest: rest::Client,
+    /// Our own username, looked up on first use and shared with the `Rasta`.
+    username: Arc<Mutex<Option<String>>>,
 }
 
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
 impl Rasta {
 
     pub async fn connect(hostname: &str) -> Result<Self> {
@@ -86,92 +149,315 @@ impl Rasta {
         let rest = rest::Client::new(hostname);
 
         let ws_url = format!("wss://{}/websocket", hostname);
-        let connection = Connection::connect(&ws_url).await?;
+        let connection = Connection::connect(&ws_url).await.map_err(Error::transport)?;
+        let handle = Arc::new(Mutex::new(connection.handle()));
 
-        Ok(Self { connection, rest  })
+        Ok(Self { ws_url, connection, handle, rest, resume: None, user: None, username: Default::default(), subscriptions: vec![], reactions: Default::default() })
     }
 
     pub fn handle(&self) -> Handle {
-        Handle { handle: self.connection.handle(), rest: self.rest.clone() }
+        Handle { handle: self.handle.clone(), rest: self.rest.clone(), username: self.username.clone() }
     }
 
-    pub async fn login(&mut self, creds: Credentials) -> Result<Option<LoginReply>> {
+    pub async fn login(&mut self, creds: Credentials) -> Result<LoginReply> {
+
+        let reply = self.connection.call("login".to_string(), vec![creds.json()]).await.map_err(Error::transport)?
+            .map_err(login_error)?;
+        let reply: LoginReply = decode(reply)?;
+        debug!("DDP Login successful");
 
-        let ticket = self.rest.login(&creds).await?;
-        debug!("HTTPS Login successful");
+        self.rest.set_login(reply.id.as_str(), &reply.token);
+        self.resume = Some(reply.token.clone());
+        self.user = Some(reply.id.clone());
 
-        Ok(self.connection.call("login".to_string(), vec![ticket.json()]).await?
-            .ok()
-            .map(serde_json::from_value)
-            .transpose()?
-        )
+        for stream in &["rooms-changed", "subscriptions-changed"] {
+            let event = format!("{}/{}", reply.id.as_str(), stream);
+            self.subscribe("stream-notify-user".into(), vec![event.into(), false.into()]).await?;
+        }
+
+        Ok(reply)
         
     }
 
+    /// Our own user id, once logged in.
+    pub fn user_id(&self) -> Option<&UserID> {
+        self.user.as_ref()
+    }
+
+    /// Asks the server to email a login code, for accounts using email two-factor.
+    pub async fn send_email_code(&mut self, user: &str) -> Result<()> {
+        self.connection.call("sendEmailCode".to_string(), vec![user.into()]).await.map_err(Error::transport)?
+            .map_err(Error::method)?;
+        Ok(())
+    }
+
     pub async fn rooms(&mut self) -> Result<Vec<Room>> {
-        let reply = self.connection.call("rooms/get".to_string(), vec![]).await??;
-        Ok(serde_json::from_value(reply)?)
+        let reply = self.connection.call("rooms/get".to_string(), vec![]).await.map_err(Error::transport)?
+            .map_err(Error::method)?;
+        decode(reply)
+    }
+
+    pub async fn room_subscriptions(&mut self) -> Result<Vec<Subscription>> {
+        let reply = self.connection.call("subscriptions/get".to_string(), vec![]).await.map_err(Error::transport)?
+            .map_err(Error::method)?;
+        decode(reply)
     }
 
-    pub fn stream(&mut self) -> &mut impl Stream<Item=ServerMessage> {
-        self.connection.stream() 
+    /// Event stream that transparently reconnects, see `recv`.
+    pub fn stream(&mut self) -> impl Stream<Item=Event> + '_ {
+        futures::stream::unfold(self, |rasta| async move {
+            match rasta.recv().await {
+                Ok(event) => Some((event, rasta)),
+                Err(e) => {
+                    warn!("Event stream terminated: {}", e);
+                    None
+                }
+            }
+        })
     }
 
-    pub async fn recv(&mut self) -> Result<ServerMessage> {
-        self.connection.recv().await.ok_or(anyhow!("fail"))
+    /// Receives the next event. If the connection dropped, it is re-established,
+    /// the session resumed and all subscriptions restored before `Event::Reconnected`
+    /// is returned; messages sent in the meantime must be backfilled by the caller.
+    pub async fn recv(&mut self) -> Result<Event> {
+        loop {
+            let event = match self.connection.recv().await {
+                Some(msg) => self.reactions.apply(Event::from(msg)),
+                None => {
+                    warn!("Connection to {} lost, reconnecting", self.ws_url);
+                    self.reconnect().await?;
+                    Event::Reconnected
+                }
+            };
+
+            // The server echoes our own typing notifications back; our username is
+            // known from the moment we send one
+            match &event {
+                Event::Typing { username, .. } if self.username.lock().unwrap().as_ref() == Some(username) => continue,
+                _ => return Ok(event),
+            }
+        }
+    }
+
+    /// Retries `restore` with exponential backoff, and only switches over to the new
+    /// connection once it is fully set up. Gives up if the session cannot be resumed.
+    async fn reconnect(&mut self) -> Result<()> {
+        let mut delay = Duration::from_secs(1);
+        let connection = loop {
+            match self.restore().await {
+                Ok(connection) => break connection,
+                Err(Error::AuthFailed(e)) => {
+                    return Err(Error::AuthFailed(format!("could not resume session: {}", e)))
+                },
+                Err(e) => {
+                    warn!("Reconnection failed: {}, retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_BACKOFF);
+                }
+            }
+        };
+
+        *self.handle.lock().unwrap() = connection.handle();
+        self.connection = connection;
+        Ok(())
+    }
+
+    /// Opens a new connection, resumes the session on it and restores all subscriptions.
+    async fn restore(&self) -> Result<Connection> {
+        let mut connection = Connection::connect(&self.ws_url).await.map_err(Error::transport)?;
+
+        let resume = self.resume.clone()
+            .or_else(|| match self.rest.token() {
+                Some(Credentials::Token(tok)) => Some(tok),
+                _ => None,
+            });
+
+        if let Some(token) = resume {
+            connection.call("login".to_string(), vec![Credentials::Token(token).json()]).await.map_err(Error::transport)?
+                .map_err(login_error)?;
+            debug!("Session resumed");
+        }
+
+        for (id, name, params) in self.subscriptions.clone() {
+            connection.subscribe(id, name, params).await.map_err(Error::transport)?;
+        }
+
+        Ok(connection)
+    }
+
+    async fn subscribe_with_id(&mut self, id: String, name: String, params: Vec<Value>) -> Result<()> {
+        self.connection.subscribe(id.clone(), name.clone(), params.clone()).await.map_err(Error::transport)?;
+        self.subscriptions.push((id, name, params));
+        Ok(())
     }
 
     pub async fn subscribe(&mut self, name: String, params: Vec<Value>) -> Result<()> {
-        let mut id = vec![0; 10];
-        random_id(&mut id);
-        let id = String::from_utf8(id)?;
-        self.connection.subscribe(id, name, params).await
+        self.subscribe_with_id(random_id(10), name, params).await
     }
 
     pub async fn subscribe_room(&mut self, room_id: String) -> Result<()> {
+        for event in &["deleteMessage", "typing"] {
+            let id = format!("{}/{}", room_id, event);
+            self.subscribe_with_id(id.clone(), "stream-notify-room".to_string(),
+             vec![ Value::String(id), Value::Bool(false) ])
+                .await?;
+        }
+
+        self.subscribe_messages(room_id).await
+    }
+
+    async fn subscribe_messages(&mut self, room_id: String) -> Result<()> {
         let id = room_id.clone();
-        Ok(self.connection.subscribe(id, "stream-room-messages".to_string(),
+        self.subscribe_with_id(id, "stream-room-messages".to_string(),
          vec![ Value::String(room_id), Value::Bool(false) ])
-            .await?)
+            .await
     }
 
     pub async fn subscribe_my_messages(&mut self) -> Result<()> {
-        self.subscribe_room("__my_messages__".to_string()).await
+        self.subscribe_messages("__my_messages__".to_string()).await
     }
 
 }
 
-fn random_id(buf: &mut [u8]) {
-    for b in buf.iter_mut() {
-        *b = fastrand::alphabetic() as u8;
-    }
+fn random_id(len: usize) -> String {
+    (0..len).map(|_| fastrand::alphabetic()).collect()
 }
 
 impl Handle {
+
+    async fn call_raw(&self, method: String, params: Vec<Value>) -> Result<MethodResult> {
+        // Don't hold the lock during the call, or every call would wait on the others
+        let mut handle = self.handle.lock().unwrap().clone();
+        handle.call(method, params).await.map_err(Error::transport)
+    }
+
+    async fn call(&self, method: String, params: Vec<Value>) -> Result<Value> {
+        self.call_raw(method, params).await?.map_err(Error::method)
+    }
+
     pub async fn send_message(&mut self, id: MessageID, room: &Room, msg: String) -> Result<()> {
         // Ignore result, we can't do anything about it anyway
-        let _ = self.handle.call("sendMessage".to_string(), vec![json!(
+        let _ = self.call_raw("sendMessage".to_string(), vec![json!(
             { "_id": id, "rid": room.id().to_string(), "msg": msg }
         )]).await?;
         Ok(())
     }
 
+    pub async fn send_thread_reply(&mut self, id: MessageID, room: &Room, thread: &MessageID, msg: String) -> Result<()> {
+        let _ = self.call_raw("sendMessage".to_string(), vec![json!(
+            { "_id": id, "rid": room.id().to_string(), "tmid": thread, "msg": msg }
+        )]).await?;
+        Ok(())
+    }
+
+    /// Root messages of the threads in a room, most recently active first.
+    pub async fn thread_list(&mut self, room: &Room, skip: usize, limit: usize) -> Result<Vec<RoomEventData>> {
+        let reply = self.call("getThreadsList".into(), vec![json!(
+            { "rid": room.id(), "skip": skip, "limit": limit }
+        )]).await?;
+        decode(reply)
+    }
+
+    pub async fn thread_messages(&mut self, thread: &MessageID, skip: usize, limit: usize) -> Result<Vec<RoomEventData>> {
+        let reply = self.call("getThreadMessages".into(), vec![json!(
+            { "tmid": thread, "skip": skip, "limit": limit }
+        )]).await?;
+        decode(reply)
+    }
+
+    pub async fn follow_thread(&mut self, thread: &MessageID) -> Result<()> {
+        self.call("followMessage".into(), vec![json!({ "mid": thread })]).await?;
+        Ok(())
+    }
+
+    pub async fn unfollow_thread(&mut self, thread: &MessageID) -> Result<()> {
+        self.call("unfollowMessage".into(), vec![json!({ "mid": thread })]).await?;
+        Ok(())
+    }
+
+    /// Starts a discussion in a new room attached to `parent`, optionally from one of
+    /// its messages, with `reply` as first message.
+    pub async fn create_discussion(&mut self, parent: &Room, message: Option<&MessageID>, name: &str, users: &[String], reply: Option<&str>) -> Result<Room> {
+        let mut params = json!({ "prid": parent.id(), "t_name": name, "users": users });
+        if let Some(message) = message {
+            params["pmid"] = json!(message);
+        }
+        if let Some(reply) = reply {
+            params["reply"] = reply.into();
+        }
+        let reply = self.call("createDiscussion".into(), vec![params]).await?;
+        decode(reply)
+    }
+
+    pub async fn update_message(&mut self, id: &MessageID, room: &Room, msg: String) -> Result<()> {
+        self.call("updateMessage".into(), vec![json!(
+            { "_id": id, "rid": room.id(), "msg": msg }
+        )]).await?;
+        Ok(())
+    }
+
+    /// Our own username, which typing notifications are sent under.
+    async fn username(&self) -> Result<String> {
+        let cached = self.username.lock().unwrap().clone();
+        if let Some(username) = cached {
+            return Ok(username)
+        }
+
+        let user_id = self.rest.user_id()
+            .ok_or_else(|| Error::Protocol("typing notifications require being logged in".into()))?;
+        let username = self.rest.user_info(&user_id).await?.username
+            .ok_or_else(|| Error::Protocol("our account has no username".into()))?;
+        *self.username.lock().unwrap() = Some(username.clone());
+        Ok(username)
+    }
+
+    pub async fn set_typing(&mut self, room: &Room, typing: bool) -> Result<()> {
+        let username = self.username().await?;
+        let event = format!("{}/typing", room.id());
+        self.call("stream-notify-room".into(), vec![ event.into(), username.into(), typing.into() ]).await?;
+        Ok(())
+    }
+
+    /// Marks all messages in the room as read, clearing its unread counters.
+    pub async fn mark_read(&mut self, room: &Room) -> Result<()> {
+        self.call("readMessages".into(), vec![ room.id().into() ]).await?;
+        Ok(())
+    }
+
+    pub async fn set_reaction(&mut self, id: &MessageID, emoji: &str, add: bool) -> Result<()> {
+        self.call("setReaction".into(), vec![ emoji.into(), json!(id), add.into() ]).await?;
+        Ok(())
+    }
+
+    pub async fn delete_message(&mut self, id: &MessageID) -> Result<()> {
+        self.call("deleteMessage".into(), vec![json!({ "_id": id })]).await?;
+        Ok(())
+    }
+
     pub async fn create_direct(&mut self, user: String) -> Result<Room> { 
-        self.handle.call("createDirectMessage".into()
-                                      , vec![user.into()])
-            .await??
-            .as_object_mut()
-            .and_then(|o| o.get_mut("rid"))
-            .and_then(|v| 
-                if let Value::String(id) = v.take() {
-                    Some(Room::Direct { id, lm: None }) //TODO check this
-                } else { None }
-            )
-            .ok_or(anyhow!("malformed createDirectMessage reply"))
+        let reply = self.call("createDirectMessage".into(), vec![user.clone().into()]).await?;
+
+        // Recent servers reply with the room itself, older ones only with its id
+        if reply["t"].is_string() {
+            return decode(reply)
+        }
+
+        let id = reply["rid"].as_str()
+            .ok_or_else(|| Error::Protocol("malformed createDirectMessage reply".into()))?;
+        let mut usernames: Vec<String> = self.username.lock().unwrap().iter().cloned().collect();
+        usernames.push(user);
+        Ok(Room::Direct { id: id.to_string(), usernames, uids: vec![], lm: None, details: Default::default() })
     }
 
     pub async fn set_default_status(&mut self, p: Presence) -> Result<()> {
-        self.handle.call("UserPresence:setDefaultStatus".into(), vec![ serde_json::to_value(p)? ]).await??;
+        self.call("UserPresence:setDefaultStatus".into(), vec![ serde_json::to_value(p)? ]).await?;
+        Ok(())
+    }
+
+    /// Sets our presence along with a custom status message.
+    pub async fn set_status(&mut self, status: &Status) -> Result<()> {
+        let message = status.message.clone().unwrap_or_default();
+        self.call("setUserStatus".into(), vec![ serde_json::to_value(status.presence)?, message.into() ]).await?;
         Ok(())
     }
 
@@ -179,12 +465,12 @@ impl Handle {
         let method = "UserPresence:".to_string() +
             if away { "away" } else { "online" };
 
-        self.handle.call(method, vec![]).await??;
+        self.call(method, vec![]).await?;
         Ok(())
     }
 
     pub async fn set_room(&mut self, room: &Room, name: String, value: Value) -> Result<MethodResult> {
-        self.handle.call("saveRoomSettings".into(), vec![ room.id().clone().into(), name.into(), value ]).await
+        self.call_raw("saveRoomSettings".into(), vec![ room.id().clone().into(), name.into(), value ]).await
     }
 
     pub async fn set_topic(&mut self, room: &Room, topic: Option<String>) -> Result<bool> {
@@ -199,9 +485,9 @@ impl Handle {
 
     pub async fn lookup_room_id(&mut self, name: String) -> Result<Option<String>> {
         let params = vec![name.clone().into(), json!([]), json!({ "users": false, "rooms": true})];
-        let response = self.handle.call("spotlight".into(), params)
-            .await??;
-        let data: Spotlight = serde_json::from_value(response)?;
+        let response = self.call("spotlight".into(), params)
+            .await?;
+        let data: Spotlight = decode(response)?;
 
         debug!("Room lookup result: {:?}", data);
         
@@ -222,11 +508,62 @@ impl Handle {
             Some(code) => vec![rid, code.into()],
             None => vec![rid]
         };
-        Ok(self.handle.call("joinRoom".into(), params).await?.is_ok())
+        Ok(self.call_raw("joinRoom".into(), params).await?.is_ok())
     }
 
     pub async fn leave_room(&mut self, rid: String) -> Result<bool> {
-        Ok(self.handle.call("leaveRoom".into(), vec![rid.into()]).await?.is_ok())
+        Ok(self.call_raw("leaveRoom".into(), vec![rid.into()]).await?.is_ok())
+    }
+
+    /// Fetches up to `limit` messages older than `before` (or the most recent ones),
+    /// oldest first.
+    pub async fn load_history(&mut self, room: &Room, before: Option<Timestamp>, limit: usize) -> Result<Vec<RoomEventData>> {
+        let params = vec![ room.id().into(), serde_json::to_value(before)?, limit.into(), Value::Null ];
+        let reply = self.call("loadHistory".into(), params).await?;
+        let mut history: History = decode(reply)?;
+        history.messages.sort_by_key(|m| m.ts);
+        Ok(history.messages)
+    }
+
+    /// Messages created, edited or deleted since `since` (the DDP side of `chat.syncMessages`).
+    /// Updated messages are returned oldest first.
+    pub async fn sync_messages(&mut self, room: &Room, since: Timestamp) -> Result<MessageSync> {
+        let params = vec![ room.id().into(), json!({ "lastUpdate": since }) ];
+        let reply = self.call("messages/get".into(), params).await?;
+        let mut sync: MessageSync = decode(reply)?;
+        sync.updated.sort_by_key(|m| m.ts);
+        Ok(sync)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn password_login_json() {
+        let creds = Credentials::Clear { user: "syn".into(), password: "hunter2".into() };
+        assert_eq!(creds.json(), json!({
+            "user": {"username": "syn"},
+            "password": {
+                "algorithm": "sha-256",
+                "digest": "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7"
+            }
+        }));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn oauth_login_json() {
+        let creds = Credentials::OAuth { service: "google".into(), access_token: "ya29.token".into(), expires_in: None };
+        assert_eq!(creds.json(), json!({
+            "oauth": {
+                "serviceName": "google",
+                "accessToken": "ya29.token",
+                "expiresIn": 3600
+            }
+        }));
+    }
+
+}
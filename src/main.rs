@@ -1,4 +1,6 @@
 use anyhow::Result;
+use log::debug;
+use rasta::Event;
 use tokio;
 
 
@@ -39,15 +41,18 @@ pub async fn main() -> Result<()> {
 
     cli.subscribe_my_messages().await?;
     cli.subscribe("stream-notify-logged".into(), vec!["user-status".into()]).await?;
-    cli.subscribe("stream-notify-user".into(), vec!["syn/rooms-changed".into()] ).await?;
 
 
-    let session = rasta::session::Session::from(&mut cli).await?;
+    let mut session = rasta::session::Session::from(&mut cli).await?;
     eprintln!("\n\nSession info:\n{:?}", session);
 
     loop {
-        let msg = cli.recv().await?;
-        eprintln!("Got message: {}", msg.pretty());
+        let event = cli.recv().await?;
+        session.apply(&event);
+        match event {
+            Event::Other(msg) => eprintln!("Got message: {}", msg.pretty()),
+            event => eprintln!("Got event: {:?}", event),
+        }
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,184 @@
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use log::debug;
+
+/// Top level element of the markdown tree the server sends in the `md` field of messages.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Block {
+    Paragraph { value: Vec<Inline> },
+    Heading { #[serde(default)] level: u8, value: Vec<Inline> },
+    Code { #[serde(default)] language: Option<String>, value: Vec<CodeLine> },
+    Quote { value: Vec<Block> },
+    UnorderedList { value: Vec<ListItem> },
+    OrderedList { value: Vec<ListItem> },
+    Tasks { value: Vec<Task> },
+    BigEmoji { value: Vec<Inline> },
+    Katex { value: String },
+    LineBreak,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Inline {
+    PlainText { value: String },
+    Bold { value: Vec<Inline> },
+    Italic { value: Vec<Inline> },
+    Strike { value: Vec<Inline> },
+    InlineCode { value: Box<Inline> },
+    Link { value: Link },
+    Image { value: Link },
+    /// `@username`, the value holding the bare username.
+    MentionUser { value: Box<Inline> },
+    /// `#channel`, the value holding the bare channel name.
+    MentionChannel { value: Box<Inline> },
+    Emoji {
+        #[serde(default)]
+        value: Option<Box<Inline>>,
+        #[serde(default, rename = "shortCode")]
+        short_code: Option<String>,
+        #[serde(default)]
+        unicode: Option<String>,
+    },
+    InlineKatex { value: String },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Link {
+    pub src: Box<Inline>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub label: Vec<Inline>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CodeLine {
+    pub value: Inline,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ListItem {
+    pub value: Vec<Inline>,
+    #[serde(default)]
+    pub number: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Task {
+    pub status: bool,
+    pub value: Vec<Inline>,
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Inline>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany { One(Inline), Many(Vec<Inline>) }
+
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(inline) => vec![inline],
+        OneOrMany::Many(inlines) => inlines,
+    })
+}
+
+/// Newer servers may send node shapes we do not know about; rather than losing the
+/// whole message, drop the tree and let callers fall back on the plain text.
+pub(crate) fn lenient<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Block>, D::Error> {
+    let raw = Value::deserialize(d)?;
+    Ok(Vec::<Block>::deserialize(&raw).unwrap_or_else(|e| {
+        debug!("Ignoring undecodable markdown {}: {}", raw, e);
+        vec![]
+    }))
+}
+
+impl Inline {
+
+    /// Children of formatting nodes.
+    pub fn children(&self) -> &[Inline] {
+        match self {
+            Inline::Bold { value } | Inline::Italic { value } | Inline::Strike { value } => value,
+            Inline::Link { value } | Inline::Image { value } => &value.label,
+            _ => &[],
+        }
+    }
+
+    /// The text this node renders to, with formatting stripped.
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        self.write_text(&mut out);
+        out
+    }
+
+    fn write_text(&self, out: &mut String) {
+        match self {
+            Inline::PlainText { value } | Inline::InlineKatex { value } => out.push_str(value),
+            Inline::InlineCode { value } => value.write_text(out),
+            Inline::MentionUser { value } => { out.push('@'); value.write_text(out) },
+            Inline::MentionChannel { value } => { out.push('#'); value.write_text(out) },
+            Inline::Emoji { unicode: Some(u), .. } => out.push_str(u),
+            Inline::Emoji { short_code: Some(code), .. } => { out.push(':'); out.push_str(code); out.push(':') },
+            Inline::Emoji { value: Some(value), .. } => value.write_text(out),
+            Inline::Link { value } if value.label.is_empty() => value.src.write_text(out),
+            other => other.children().iter().for_each(|c| c.write_text(out)),
+        }
+    }
+
+}
+
+impl Block {
+
+    /// Inline content of the block, if it directly holds any.
+    pub fn inlines(&self) -> &[Inline] {
+        match self {
+            Block::Paragraph { value } | Block::Heading { value, .. } | Block::BigEmoji { value } => value,
+            _ => &[],
+        }
+    }
+
+    /// Calls `f` on every inline node of the block, depth first.
+    pub fn walk(&self, f: &mut impl FnMut(&Inline)) {
+        fn walk_inline(inline: &Inline, f: &mut impl FnMut(&Inline)) {
+            f(inline);
+            inline.children().iter().for_each(|c| walk_inline(c, f));
+        }
+
+        match self {
+            Block::Quote { value } => value.iter().for_each(|b| b.walk(f)),
+            Block::UnorderedList { value } | Block::OrderedList { value } => {
+                value.iter().flat_map(|item| &item.value).for_each(|i| walk_inline(i, f))
+            },
+            Block::Tasks { value } => value.iter().flat_map(|task| &task.value).for_each(|i| walk_inline(i, f)),
+            Block::Code { value, .. } => value.iter().for_each(|line| walk_inline(&line.value, f)),
+            other => other.inlines().iter().for_each(|i| walk_inline(i, f)),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn deserialize_paragraph() {
+        let md: Vec<Block> = serde_json::from_str(r#"[{"type":"PARAGRAPH","value":[
+            {"type":"PLAIN_TEXT","value":"hello "},
+            {"type":"MENTION_USER","value":{"type":"PLAIN_TEXT","value":"syn"}},
+            {"type":"PLAIN_TEXT","value":" "},
+            {"type":"BOLD","value":[{"type":"PLAIN_TEXT","value":"look"}]},
+            {"type":"EMOJI","value":{"type":"PLAIN_TEXT","value":"moneybag"},"shortCode":"moneybag"},
+            {"type":"SOMETHING_NEW","value":42}
+        ]}]"#).unwrap();
+
+        let text: String = md[0].inlines().iter().map(Inline::text).collect();
+        assert_eq!(text, "hello @syn look:moneybag:");
+
+        let mut mentions = vec![];
+        md[0].walk(&mut |i| if let Inline::MentionUser { value } = i { mentions.push(value.text()) });
+        assert_eq!(mentions, vec!["syn"]);
+    }
+
+}
@@ -1,9 +1,11 @@
-use reqwest::{Method, RequestBuilder};
-use anyhow::{Result, anyhow};
-use serde::Deserialize;
+use reqwest::{Body, Method, RequestBuilder, Response, StatusCode, header::HeaderMap, multipart::{Form, Part}};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{json, Value};
+use futures::{Stream, TryStreamExt, stream};
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
 use log::debug;
 
-use crate::{Credentials, schema::{Room, ShortUser}};
+use crate::{Credentials, Error, Result, TwoFactorMethod, error::decode, schema::{Presence, Room, ShortUser, Status, Team, TeamMember, User}};
 
 #[derive(Clone,Debug)]
 struct Login {
@@ -11,26 +13,142 @@ struct Login {
     token: String,
 }
 
+/// Request quota reported by the server in the `X-RateLimit-*` headers.
+#[derive(Clone,Copy,Debug)]
+pub struct RateLimit {
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset: SystemTime,
+}
+
+impl RateLimit {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let get = |name: &str| -> Option<u64> { headers.get(name)?.to_str().ok()?.parse().ok() };
+        Some(RateLimit {
+            limit: get("x-ratelimit-limit")?,
+            remaining: get("x-ratelimit-remaining")?,
+            reset: UNIX_EPOCH + Duration::from_millis(get("x-ratelimit-reset")?),
+        })
+    }
+
+    /// Time until the quota is replenished.
+    pub fn reset_in(&self) -> Duration {
+        self.reset.duration_since(SystemTime::now()).unwrap_or_default()
+    }
+}
+
+const MAX_RETRIES: usize = 3;
+const PAGE_SIZE: usize = 100;
+
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+impl Order {
+    fn value(self) -> i8 {
+        match self {
+            Order::Ascending => 1,
+            Order::Descending => -1,
+        }
+    }
+}
+
+/// One page of a paginated listing.
+#[derive(Clone,Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub offset: usize,
+    /// Size of the whole listing, when the server tells.
+    pub total: Option<usize>,
+}
+
+impl<T: DeserializeOwned> Page<T> {
+    /// `offset` is the one we asked for, in case the server does not echo it.
+    fn from_reply(mut reply: Value, key: &str, offset: usize) -> Result<Self> {
+        let items: Vec<T> = decode(reply[key].take())?;
+        let offset = reply["offset"].as_u64().map(|o| o as usize).unwrap_or(offset);
+        let total = reply["total"].as_u64().map(|t| t as usize);
+        Ok(Page { items, offset, total })
+    }
+
+    /// Offset of the following page, if there is one. Without a total, listings
+    /// are walked until an empty page comes back.
+    pub fn next_offset(&self) -> Option<usize> {
+        let next = self.offset + self.items.len();
+        match self.total {
+            _ if self.items.is_empty() => None,
+            Some(total) if next >= total => None,
+            _ => Some(next),
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum RoomKind {
+    Channel,
+    Group,
+}
+
+impl RoomKind {
+    pub fn of(room: &Room) -> Result<Self> {
+        match room {
+            Room::Chat{..} => Ok(RoomKind::Channel),
+            Room::Private{..} => Ok(RoomKind::Group),
+            _ => Err(Error::Protocol(format!("{} is neither a channel nor a group", room.id()))),
+        }
+    }
+
+    fn endpoint(self, method: &str) -> String {
+        format!("v1/{}.{}", self.plural(), method)
+    }
+
+    fn singular(self) -> &'static str {
+        match self {
+            RoomKind::Channel => "channel",
+            RoomKind::Group => "group",
+        }
+    }
+
+    fn plural(self) -> &'static str {
+        match self {
+            RoomKind::Channel => "channels",
+            RoomKind::Group => "groups",
+        }
+    }
+}
+
 #[derive(Clone,Debug)]
 pub struct Client {
+    server_url: String,
     base_url: String,
     client: reqwest::Client,
-    login: Option<Login>,
+    /// Shared by all clones, so that handles created before logging in see the login.
+    login: Arc<Mutex<Option<Login>>>,
+    /// Rocket.Chat limits each endpoint separately.
+    rate_limits: Arc<Mutex<HashMap<String, RateLimit>>>,
 }
 
 
 impl Client {
 
     pub fn token(&self) -> Option<Credentials> {
-        self.login.as_ref().map(|l| Credentials::Token(l.token.clone()))
+        self.login.lock().unwrap().as_ref().map(|l| Credentials::Token(l.token.clone()))
+    }
+
+    pub(crate) fn user_id(&self) -> Option<String> {
+        self.login.lock().unwrap().as_ref().map(|l| l.user_id.clone())
     }
 
     fn request(&self, method: Method, url: &str) -> RequestBuilder {
-        let url = self.base_url.clone() + url;
+        self.request_url(method, self.base_url.clone() + url)
+    }
 
+    fn request_url(&self, method: Method, url: String) -> RequestBuilder {
         let req = self.client.request(method, url);
 
-        if let Some(login) = &self.login {
+        if let Some(login) = self.login.lock().unwrap().clone() {
             req.header("X-User-Id", &login.user_id)
                .header("X-Auth-Token", &login.token)
         } else {
@@ -41,21 +159,87 @@ impl Client {
 
     pub fn new(host: &str) -> Self {
 
-        let base_url = format!("https://{}/api/", host);
+        let server_url = format!("https://{}", host);
+        let base_url = format!("{}/api/", server_url);
         let client = reqwest::Client::new();
-        Self { base_url, client, login: None }
+        Self { server_url, base_url, client, login: Default::default(), rate_limits: Default::default() }
+
+    }
+
+    /// Quota of an endpoint (e.g. `v1/users.info`) as of its last response.
+    pub fn rate_limit(&self, endpoint: &str) -> Option<RateLimit> {
+        self.rate_limits.lock().unwrap().get(endpoint).copied()
+    }
+
+    /// Sends a request, keeping track of the rate limit of its endpoint. Idempotent
+    /// requests are delayed while the quota is exhausted and retried when refused
+    /// with 429; others fail with `Error::RateLimited`.
+    async fn send(&self, req: RequestBuilder, idempotent: bool) -> Result<Response> {
+        let mut request = req.build()?;
+        let endpoint = request.url().path().trim_start_matches("/api/").to_string();
+        let mut retries = 0;
+        loop {
+            if idempotent {
+                if let Some(limit) = self.rate_limit(&endpoint).filter(|l| l.remaining == 0) {
+                    debug!("Rate limit of {} exhausted, waiting {:?}", endpoint, limit.reset_in());
+                    tokio::time::sleep(limit.reset_in()).await;
+                }
+            }
+
+            let next = if idempotent { request.try_clone() } else { None };
+            let response = self.client.execute(request).await?;
+
+            let limit = RateLimit::from_headers(response.headers());
+            if let Some(limit) = limit {
+                self.rate_limits.lock().unwrap().insert(endpoint.clone(), limit);
+            }
+
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response)
+            }
+
+            let retry_after = limit.map(|l| l.reset_in())
+                .or_else(|| response.headers().get("retry-after")?
+                                   .to_str().ok()?
+                                   .parse().ok()
+                                   .map(Duration::from_secs));
+
+            match next {
+                Some(next) if retries < MAX_RETRIES => {
+                    let wait = retry_after.unwrap_or(Duration::from_secs(1));
+                    debug!("Rate limited, retrying in {:?}", wait);
+                    tokio::time::sleep(wait).await;
+                    request = next;
+                    retries += 1;
+                },
+                _ => return Err(Error::RateLimited { retry_after }),
+            }
+        }
+    }
 
+    pub(crate) fn set_login(&mut self, user_id: &str, token: &str) {
+        *self.login.lock().unwrap() = Some(Login { user_id: user_id.to_string(), token: token.to_string() });
     }
 
     pub async fn login(&mut self, creds: &Credentials) -> Result<Credentials> {
 
         #[derive(Deserialize)]
-        #[serde(tag="status", content="data")]
+        #[serde(rename_all="camelCase")]
+        struct LoginData { auth_token: String, user_id: String }
+
+        #[derive(Deserialize)]
+        struct ErrorDetails { method: TwoFactorMethod }
+
+        #[derive(Deserialize)]
+        #[serde(tag="status")]
         #[serde(rename_all="camelCase")]
         enum LoginResult {
-            #[serde(rename_all="camelCase")]
-            Success { auth_token: String, user_id: String },
-            Error {},
+            Success { data: LoginData },
+            Error {
+                #[serde(default)] error: Option<String>,
+                #[serde(default)] message: Option<String>,
+                #[serde(default)] details: Option<ErrorDetails>,
+            },
         }
 
         let req = self.request(Method::POST, "v1/login");
@@ -66,45 +250,463 @@ impl Client {
             },
             Credentials::Token(tok) => {
                 req.form(&[("resume", tok)])
-            }
+            },
+            Credentials::PersonalToken { user_id, token } => {
+                self.set_login(user_id, token);
+                return Ok(Credentials::Token(token.clone()))
+            },
+            Credentials::TwoFactor { login, code } => match &**login {
+                Credentials::Clear { user, password } => {
+                    req.form(&[("user", user), ("password", password), ("code", code)])
+                },
+                _ => return Err(Error::Protocol("two-factor codes only apply to password logins".into())),
+            },
+            Credentials::OAuth { .. } => {
+                req.json(&creds.json()["oauth"])
+            },
+            Credentials::Ldap { .. } | Credentials::Cas { .. } | Credentials::Saml { .. } => {
+                req.json(&creds.json())
+            },
         };
 
         debug!("Sending login request {:?}", req);
-        let reply: LoginResult = req.send()
+        let reply: Value = self.send(req, false)
                        .await?
                        .json()
                        .await?;
+        let reply: LoginResult = decode(reply)?;
 
         match reply {
-            LoginResult::Success { auth_token, user_id } => {
-                self.login = Some( Login { user_id, token: auth_token.clone() });
+            LoginResult::Success { data: LoginData { auth_token, user_id } } => {
+                *self.login.lock().unwrap() = Some( Login { user_id, token: auth_token.clone() });
                 Ok( Credentials::Token(auth_token) )
             },
-            LoginResult::Error {} => {
-                Err(anyhow!("Login failed"))
+            LoginResult::Error { error: Some(e), details: Some(ErrorDetails { method }), .. } if e == "totp-required" => {
+                Err(Error::TwoFactorRequired(method))
+            },
+            LoginResult::Error { error, message, .. } => {
+                Err(Error::AuthFailed(message.or(error).unwrap_or_default()))
             }
         }
 
     }
 
+    /// All members of a room, walking every page.
     pub async fn channel_members(&self, room: &Room) -> Result<Vec<ShortUser>> {
+        self.members_stream(room).try_collect().await
+    }
 
-        #[derive(Deserialize)]
-        struct Response { members: Vec<ShortUser> }
+    pub async fn members_page(&self, room: &Room, offset: usize, count: usize, sort: Option<(&str, Order)>) -> Result<Page<ShortUser>> {
 
         let endpoint = match room {
             Room::Chat{..} => "v1/channels.members",
             Room::Private{..} => "v1/groups.members",
-            _ => return Ok(vec![]),
+            Room::Direct{..} => "v1/im.members",
+            _ => return Ok(Page { items: vec![], offset, total: Some(0) }),
         };
 
-        Ok(self.request(Method::GET, endpoint)
-               .query(&[("roomId", room.id())])
-               .send()
-               .await?
-               .json::<Response>()
-               .await?
-               .members)
+        let mut query = vec![
+            ("roomId", room.id().to_string()),
+            ("offset", offset.to_string()),
+            ("count", count.to_string()),
+        ];
+        if let Some((field, order)) = sort {
+            query.push(("sort", format!("{{\"{}\":{}}}", field, order.value())));
+        }
+
+        let req = self.request(Method::GET, endpoint).query(&query);
+        Page::from_reply(parse(self.send(req, true).await?).await?, "members", offset)
     }
 
-}
\ No newline at end of file
+    /// Members sorted by their role in the room (owners first, then moderators...).
+    pub async fn members_by_role(&self, room: &Room, offset: usize, count: usize) -> Result<Page<ShortUser>> {
+        let req = self.request(Method::GET, "v1/rooms.membersOrderedByRole")
+            .query(&[("roomId", room.id().to_string()), ("offset", offset.to_string()), ("count", count.to_string())]);
+        Page::from_reply(parse(self.send(req, true).await?).await?, "members", offset)
+    }
+
+    pub fn members_stream<'a>(&'a self, room: &'a Room) -> impl Stream<Item=Result<ShortUser>> + 'a {
+        stream::try_unfold(Some(0), move |offset| async move {
+            let offset = match offset {
+                Some(offset) => offset,
+                None => return Ok::<_, Error>(None),
+            };
+            let page = self.members_page(room, offset, PAGE_SIZE, None).await?;
+            let next = page.next_offset();
+            Ok::<_, Error>(Some((stream::iter(page.items.into_iter().map(Ok::<_, Error>)), next)))
+        }).try_flatten()
+    }
+
+    async fn room_get(&self, kind: RoomKind, method: &str, query: &[(&str, &str)]) -> Result<Value> {
+        let req = self.request(Method::GET, &kind.endpoint(method)).query(query);
+        parse(self.send(req, true).await?).await
+    }
+
+    async fn room_post(&self, kind: RoomKind, method: &str, body: Value) -> Result<Value> {
+        let req = self.request(Method::POST, &kind.endpoint(method)).json(&body);
+        parse(self.send(req, false).await?).await
+    }
+
+    async fn room_update(&self, room: &Room, method: &str, mut body: Value) -> Result<Value> {
+        body["roomId"] = room.id().into();
+        self.room_post(RoomKind::of(room)?, method, body).await
+    }
+
+    pub async fn create_room(&self, kind: RoomKind, name: &str, members: &[String], read_only: bool) -> Result<Room> {
+        let mut reply = self.room_post(kind, "create", json!({ "name": name, "members": members, "readOnly": read_only })).await?;
+        decode(reply[kind.singular()].take())
+    }
+
+    pub async fn room_info(&self, kind: RoomKind, room_id: &str) -> Result<Room> {
+        let mut reply = self.room_get(kind, "info", &[("roomId", room_id)]).await?;
+        decode(reply[kind.singular()].take())
+    }
+
+    pub async fn room_info_by_name(&self, kind: RoomKind, name: &str) -> Result<Room> {
+        let mut reply = self.room_get(kind, "info", &[("roomName", name)]).await?;
+        decode(reply[kind.singular()].take())
+    }
+
+    /// All rooms of this kind on the server (private groups require admin rights).
+    pub async fn list_rooms(&self, kind: RoomKind, offset: usize, count: usize) -> Result<Page<Room>> {
+        let method = match kind {
+            RoomKind::Channel => "list",
+            RoomKind::Group => "listAll",
+        };
+        self.room_page(kind, method, offset, count).await
+    }
+
+    pub async fn list_joined_rooms(&self, kind: RoomKind, offset: usize, count: usize) -> Result<Page<Room>> {
+        let method = match kind {
+            RoomKind::Channel => "list.joined",
+            RoomKind::Group => "list",
+        };
+        self.room_page(kind, method, offset, count).await
+    }
+
+    async fn room_page(&self, kind: RoomKind, method: &str, offset: usize, count: usize) -> Result<Page<Room>> {
+        let (from, count) = (offset.to_string(), count.to_string());
+        let reply = self.room_get(kind, method, &[("offset", from.as_str()), ("count", count.as_str())]).await?;
+        Page::from_reply(reply, kind.plural(), offset)
+    }
+
+    pub async fn invite(&self, room: &Room, user_id: &str) -> Result<Room> {
+        let kind = RoomKind::of(room)?;
+        let mut reply = self.room_update(room, "invite", json!({ "userId": user_id })).await?;
+        decode(reply[kind.singular()].take())
+    }
+
+    pub async fn kick(&self, room: &Room, user_id: &str) -> Result<Room> {
+        let kind = RoomKind::of(room)?;
+        let mut reply = self.room_update(room, "kick", json!({ "userId": user_id })).await?;
+        decode(reply[kind.singular()].take())
+    }
+
+    pub async fn rename(&self, room: &Room, name: &str) -> Result<Room> {
+        let kind = RoomKind::of(room)?;
+        let mut reply = self.room_update(room, "rename", json!({ "name": name })).await?;
+        decode(reply[kind.singular()].take())
+    }
+
+    pub async fn archive(&self, room: &Room) -> Result<()> {
+        self.room_update(room, "archive", json!({})).await?;
+        Ok(())
+    }
+
+    pub async fn unarchive(&self, room: &Room) -> Result<()> {
+        self.room_update(room, "unarchive", json!({})).await?;
+        Ok(())
+    }
+
+    pub async fn set_topic(&self, room: &Room, topic: &str) -> Result<()> {
+        self.room_update(room, "setTopic", json!({ "topic": topic })).await?;
+        Ok(())
+    }
+
+    pub async fn set_description(&self, room: &Room, description: &str) -> Result<()> {
+        self.room_update(room, "setDescription", json!({ "description": description })).await?;
+        Ok(())
+    }
+
+    pub async fn set_announcement(&self, room: &Room, announcement: &str) -> Result<()> {
+        self.room_update(room, "setAnnouncement", json!({ "announcement": announcement })).await?;
+        Ok(())
+    }
+
+    pub async fn set_read_only(&self, room: &Room, read_only: bool) -> Result<Room> {
+        let kind = RoomKind::of(room)?;
+        let mut reply = self.room_update(room, "setReadOnly", json!({ "readOnly": read_only })).await?;
+        decode(reply[kind.singular()].take())
+    }
+
+    /// Discussions started from a room.
+    pub async fn discussions(&self, room: &Room, offset: usize, count: usize) -> Result<Page<Room>> {
+        let req = self.request(Method::GET, "v1/rooms.getDiscussions")
+            .query(&[("roomId", room.id().to_string()), ("offset", offset.to_string()), ("count", count.to_string())]);
+        Page::from_reply(parse(self.send(req, true).await?).await?, "discussions", offset)
+    }
+
+    async fn set_role(&self, room: &Room, method: &str, user_id: &str) -> Result<()> {
+        self.room_update(room, method, json!({ "userId": user_id })).await?;
+        Ok(())
+    }
+
+    pub async fn add_owner(&self, room: &Room, user_id: &str) -> Result<()> {
+        self.set_role(room, "addOwner", user_id).await
+    }
+
+    pub async fn remove_owner(&self, room: &Room, user_id: &str) -> Result<()> {
+        self.set_role(room, "removeOwner", user_id).await
+    }
+
+    pub async fn add_moderator(&self, room: &Room, user_id: &str) -> Result<()> {
+        self.set_role(room, "addModerator", user_id).await
+    }
+
+    pub async fn remove_moderator(&self, room: &Room, user_id: &str) -> Result<()> {
+        self.set_role(room, "removeModerator", user_id).await
+    }
+
+    pub async fn add_leader(&self, room: &Room, user_id: &str) -> Result<()> {
+        self.set_role(room, "addLeader", user_id).await
+    }
+
+    pub async fn remove_leader(&self, room: &Room, user_id: &str) -> Result<()> {
+        self.set_role(room, "removeLeader", user_id).await
+    }
+
+    async fn users_get(&self, method: &str, query: &[(&str, String)]) -> Result<Value> {
+        let req = self.request(Method::GET, &format!("v1/users.{}", method)).query(query);
+        parse(self.send(req, true).await?).await
+    }
+
+    pub async fn user_info(&self, user_id: &str) -> Result<User> {
+        let mut reply = self.users_get("info", &[("userId", user_id.to_string())]).await?;
+        decode(reply["user"].take())
+    }
+
+    pub async fn user_info_by_name(&self, username: &str) -> Result<User> {
+        let mut reply = self.users_get("info", &[("username", username.to_string())]).await?;
+        decode(reply["user"].take())
+    }
+
+    /// Users matching a Mongo-style `query`, e.g. `json!({ "active": true })`.
+    pub async fn list_users(&self, query: Option<Value>, offset: usize, count: usize) -> Result<Page<User>> {
+        let mut params = vec![("offset", offset.to_string()), ("count", count.to_string())];
+        if let Some(query) = query {
+            params.push(("query", query.to_string()));
+        }
+        Page::from_reply(self.users_get("list", &params).await?, "users", offset)
+    }
+
+    pub async fn user_presence(&self, user_id: &str) -> Result<Presence> {
+        let mut reply = self.users_get("getPresence", &[("userId", user_id.to_string())]).await?;
+        decode(reply["presence"].take())
+    }
+
+    pub async fn user_avatar(&self, username: &str) -> Result<Vec<u8>> {
+        let req = self.request(Method::GET, "v1/users.getAvatar")
+            .query(&[("username", username)]);
+        let response = self.send(req, true).await?;
+
+        if !response.status().is_success() {
+            return Err(Error::status(response.status(), &Value::Null))
+        }
+
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Sets our own presence and custom status message.
+    pub async fn set_status(&self, status: &Status) -> Result<()> {
+        let req = self.request(Method::POST, "v1/users.setStatus")
+            .json(&json!({ "status": status.presence, "message": status.message.as_deref().unwrap_or("") }));
+        parse::<Value>(self.send(req, false).await?).await?;
+        Ok(())
+    }
+
+    pub async fn set_avatar(&self, image: impl Into<Body>, filename: &str, mime: &str) -> Result<()> {
+        let part = Part::stream(image)
+            .file_name(filename.to_string())
+            .mime_str(mime)?;
+
+        let req = self.request(Method::POST, "v1/users.setAvatar")
+            .multipart(Form::new().part("image", part));
+        parse::<Value>(self.send(req, false).await?).await?;
+        Ok(())
+    }
+
+    pub async fn set_avatar_url(&self, url: &str) -> Result<()> {
+        let req = self.request(Method::POST, "v1/users.setAvatar")
+            .json(&json!({ "avatarUrl": url }));
+        parse::<Value>(self.send(req, false).await?).await?;
+        Ok(())
+    }
+
+    async fn teams_get(&self, method: &str, query: &[(&str, String)]) -> Result<Value> {
+        let req = self.request(Method::GET, &format!("v1/teams.{}", method)).query(query);
+        parse(self.send(req, true).await?).await
+    }
+
+    async fn teams_post(&self, method: &str, body: Value) -> Result<Value> {
+        let req = self.request(Method::POST, &format!("v1/teams.{}", method)).json(&body);
+        parse(self.send(req, false).await?).await
+    }
+
+    /// Teams we are a member of.
+    pub async fn list_teams(&self, offset: usize, count: usize) -> Result<Page<Team>> {
+        let reply = self.teams_get("list", &[("offset", offset.to_string()), ("count", count.to_string())]).await?;
+        Page::from_reply(reply, "teams", offset)
+    }
+
+    pub async fn team_info(&self, team_id: &str) -> Result<Team> {
+        let mut reply = self.teams_get("info", &[("teamId", team_id.to_string())]).await?;
+        decode(reply["teamInfo"].take())
+    }
+
+    /// Rooms belonging to a team, not including its main room.
+    pub async fn team_rooms(&self, team_id: &str, offset: usize, count: usize) -> Result<Page<Room>> {
+        let reply = self.teams_get("listRooms", &[
+            ("teamId", team_id.to_string()),
+            ("offset", offset.to_string()),
+            ("count", count.to_string()),
+        ]).await?;
+        Page::from_reply(reply, "rooms", offset)
+    }
+
+    pub async fn team_members(&self, team_id: &str, offset: usize, count: usize) -> Result<Page<TeamMember>> {
+        let reply = self.teams_get("members", &[
+            ("teamId", team_id.to_string()),
+            ("offset", offset.to_string()),
+            ("count", count.to_string()),
+        ]).await?;
+        Page::from_reply(reply, "members", offset)
+    }
+
+    /// Moves existing rooms into a team.
+    pub async fn add_team_rooms(&self, team_id: &str, room_ids: &[&str]) -> Result<Vec<Room>> {
+        let mut reply = self.teams_post("addRooms", json!({ "teamId": team_id, "rooms": room_ids })).await?;
+        decode(reply["rooms"].take())
+    }
+
+    pub async fn remove_team_room(&self, team_id: &str, room_id: &str) -> Result<()> {
+        self.teams_post("removeRoom", json!({ "teamId": team_id, "roomId": room_id })).await?;
+        Ok(())
+    }
+
+    pub async fn add_team_members(&self, team_id: &str, user_ids: &[&str]) -> Result<()> {
+        let members: Vec<Value> = user_ids.iter()
+            .map(|id| json!({ "userId": id, "roles": ["member"] }))
+            .collect();
+        self.teams_post("addMembers", json!({ "teamId": team_id, "members": members })).await?;
+        Ok(())
+    }
+
+    pub async fn remove_team_member(&self, team_id: &str, user_id: &str) -> Result<()> {
+        self.teams_post("removeMember", json!({ "teamId": team_id, "userId": user_id })).await?;
+        Ok(())
+    }
+
+    pub async fn upload_file(&self, room: &Room, data: impl Into<Body>, filename: &str, mime: &str, description: Option<&str>) -> Result<()> {
+
+        let part = Part::stream(data)
+            .file_name(filename.to_string())
+            .mime_str(mime)?;
+
+        let mut form = Form::new().part("file", part);
+        if let Some(description) = description {
+            form = form.text("description", description.to_string());
+        }
+
+        let req = self.request(Method::POST, &format!("v1/rooms.upload/{}", room.id()))
+            .multipart(form);
+        let response = self.send(req, false).await?;
+        parse::<Value>(response).await?;
+
+        Ok(())
+    }
+
+    /// Fetches an uploaded file. `url` can be absolute or relative to the server,
+    /// as found in `Attachment::title_link`. Our credentials are only sent along
+    /// to the server itself, never to third-party hosts.
+    pub async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let url = if url.starts_with("http://") || url.starts_with("https://") {
+            url.to_string()
+        } else {
+            format!("{}/{}", self.server_url, url.trim_start_matches('/'))
+        };
+
+        // Other hosts' rate limits are none of our business either
+        let response = if url == self.server_url || url.starts_with(&format!("{}/", self.server_url)) {
+            self.send(self.request_url(Method::GET, url), true).await?
+        } else {
+            self.client.get(url).send().await?
+        };
+
+        if !response.status().is_success() {
+            return Err(Error::status(response.status(), &Value::Null))
+        }
+
+        Ok(response.bytes().await?.to_vec())
+    }
+
+}
+
+async fn parse<T: DeserializeOwned>(response: Response) -> Result<T> {
+    let status = response.status();
+    let text = response.text().await?;
+    match serde_json::from_str::<Value>(&text) {
+        Ok(body) if status.is_success() => decode(body),
+        Ok(body) => Err(Error::status(status, &body)),
+        Err(_) if !status.is_success() => Err(Error::status(status, &Value::Null)),
+        Err(error) => Err(Error::Decode { error, raw: Value::String(text) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn page(reply: Value, offset: usize) -> Page<Value> {
+        Page::from_reply(reply, "members", offset).unwrap()
+    }
+
+    #[test]
+    fn walk_pages() {
+        // The server capped our count of 100 to 50
+        let first = page(json!({ "members": vec![json!({}); 50], "offset": 0, "count": 50, "total": 120 }), 0);
+        assert_eq!(first.next_offset(), Some(50));
+
+        let last = page(json!({ "members": vec![json!({}); 20], "offset": 100, "count": 20, "total": 120 }), 100);
+        assert_eq!(last.next_offset(), None);
+
+        let empty = page(json!({ "members": [], "offset": 120, "total": 120 }), 120);
+        assert_eq!(empty.next_offset(), None);
+    }
+
+    #[test]
+    fn walk_pages_without_total() {
+        let partial = page(json!({ "members": vec![json!({}); 50] }), 100);
+        assert_eq!(partial.offset, 100);
+        assert_eq!(partial.next_offset(), Some(150));
+
+        let empty = page(json!({ "members": [] }), 150);
+        assert_eq!(empty.next_offset(), None);
+    }
+
+    #[test]
+    fn rate_limit_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-limit", "10".parse().unwrap());
+        headers.insert("x-ratelimit-remaining", "0".parse().unwrap());
+        assert!(RateLimit::from_headers(&headers).is_none());
+
+        headers.insert("x-ratelimit-reset", "1618836010099".parse().unwrap());
+        let limit = RateLimit::from_headers(&headers).unwrap();
+        assert_eq!(limit.limit, 10);
+        assert_eq!(limit.remaining, 0);
+        assert_eq!(limit.reset, UNIX_EPOCH + Duration::from_millis(1618836010099));
+        assert_eq!(limit.reset_in(), Duration::default());
+    }
+
+}
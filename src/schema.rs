@@ -1,18 +1,26 @@
 use serde::{Serialize, Deserialize};
 use serde_json::{Map, Value};
-use siderite::protocol::Timestamp;
+use std::collections::BTreeMap;
+use crate::markdown::Block;
+pub use crate::timestamp::Timestamp;
 use log::debug;
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct UserID(String);
 
+impl UserID {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 impl PartialEq<str> for UserID {
     fn eq(&self, other: &str) -> bool {
         self.0 == other
     }
 } 
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct MessageID(String);
 
 impl MessageID {
@@ -34,16 +42,59 @@ pub enum Presence {
     Offline,
 }
 
+impl Presence {
+    /// Decodes the numeric status used by the `user-status` stream.
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            0 => Some(Presence::Offline),
+            1 => Some(Presence::Online),
+            2 => Some(Presence::Away),
+            3 => Some(Presence::Busy),
+            _ => None,
+        }
+    }
+}
+
+/// Presence together with the custom status message users can set.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Status {
+    pub presence: Presence,
+    pub message: Option<String>,
+}
+
+impl Status {
+    /// An empty message is the same as no message.
+    pub fn new(presence: Presence, message: Option<String>) -> Self {
+        Status { presence, message: message.filter(|m| !m.is_empty()) }
+    }
+}
+
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
     #[serde(rename = "_id")] pub id: UserID,
-    pub created_at: Timestamp,
-    pub roles: Vec<String>,
+    #[serde(default)] pub created_at: Option<Timestamp>,
+    #[serde(default)] pub roles: Vec<String>,
     #[serde(rename = "type")] pub usertype: String,
     pub active: bool,
     pub username: Option<String>,
     pub name: Option<String>,
+    #[serde(default)] pub status: Option<Presence>,
+    #[serde(default)] pub status_text: Option<String>,
+    #[serde(default)] pub emails: Vec<Email>,
+}
+
+impl User {
+    pub fn status(&self) -> Option<Status> {
+        self.status.map(|p| Status::new(p, self.status_text.clone()))
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Email {
+    pub address: String,
+    #[serde(default)]
+    pub verified: bool,
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
@@ -52,7 +103,7 @@ pub struct ShortUser {
     #[serde(rename = "_id")]
     pub id: UserID,
     pub username: String,
-    #[serde(rename = "name")]
+    #[serde(default, rename = "name")]
     pub realname: String,
     #[serde(default, skip_serializing_if="Option::is_none")]
     pub status: Option<Presence>,
@@ -66,6 +117,48 @@ pub struct LoginReply {
     pub token_expires: Timestamp,
 }
 
+/// Fields common to every kind of room. Anything we do not model is kept in `extra`.
+#[derive(Eq, PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomDetails {
+    #[serde(default, rename = "u", skip_serializing_if="Option::is_none")]
+    pub creator: Option<ShortUser>,
+    /// Display name, when the room name is a slug.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub fname: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub announcement: Option<String>,
+    #[serde(default)]
+    pub users_count: u64,
+    #[serde(default)]
+    pub archived: bool,
+    /// New users join default rooms automatically.
+    #[serde(default)]
+    pub default: bool,
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Only users with the right permission may post; others can only reply in threads.
+    #[serde(default)]
+    pub broadcast: bool,
+    #[serde(default, rename = "_updatedAt", skip_serializing_if="Option::is_none")]
+    pub updated_at: Option<Timestamp>,
+    /// Raw last message; see `Room::last_message`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub last_message: Option<Value>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub team_id: Option<String>,
+    /// This is the main room of team `team_id`.
+    #[serde(default)]
+    pub team_main: bool,
+    /// Parent room, for discussions.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub prid: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "t")]
 pub enum Room {
@@ -73,16 +166,20 @@ pub enum Room {
     Direct {
         #[serde(rename = "_id")]
         id: String,
+        #[serde(default)]
+        usernames: Vec<String>,
+        #[serde(default)]
+        uids: Vec<UserID>,
         #[serde(default, skip_serializing_if="Option::is_none")]
-        lm: Option<Timestamp>
+        lm: Option<Timestamp>,
+        #[serde(flatten)]
+        details: RoomDetails,
     },
     #[serde(rename = "c")]
     Chat {
         #[serde(rename = "_id")]
         id: String,
         name: String,
-        //#[serde(rename = "u")]
-        //creator: User,
         #[serde(default, skip_serializing_if="Option::is_none")]
         topic: Option<String>,
         #[serde(default)]
@@ -90,14 +187,14 @@ pub enum Room {
 
         #[serde(default, skip_serializing_if="Option::is_none")]
         lm: Option<Timestamp>,
+        #[serde(flatten)]
+        details: RoomDetails,
     },
     #[serde(rename = "p")]
     Private {
         #[serde(rename = "_id")]
         id: String,
         name: String,
-        //#[serde(rename = "u")]
-        //creator: User,
         #[serde(default, skip_serializing_if="Option::is_none")]
         topic: Option<String>,
         #[serde(default)]
@@ -107,6 +204,8 @@ pub enum Room {
         lm: Option<Timestamp>,
 
         ro: bool,       
+        #[serde(flatten)]
+        details: RoomDetails,
     },
     #[serde(rename = "l")]
     LiveChat {
@@ -115,6 +214,8 @@ pub enum Room {
 
         #[serde(default, skip_serializing_if="Option::is_none")]
         lm: Option<Timestamp>,
+        #[serde(flatten)]
+        details: RoomDetails,
     },
 }
 
@@ -128,6 +229,54 @@ impl Room {
         }
     }
 
+    pub fn details(&self) -> &RoomDetails {
+        match self {
+            Room::Chat{details,..} => details,
+            Room::Direct{details,..} => details,
+            Room::LiveChat{details,..} => details,
+            Room::Private{details,..} => details,
+        }
+    }
+
+    /// Name to show for the room: `fname` if set, else the name, else the
+    /// other participants of a direct room.
+    pub fn display_name(&self) -> String {
+        if let Some(fname) = &self.details().fname {
+            return fname.clone()
+        }
+        match self {
+            Room::Chat{name,..} | Room::Private{name,..} => name.clone(),
+            Room::Direct{usernames,..} => usernames.join(", "),
+            Room::LiveChat{id,..} => id.clone(),
+        }
+    }
+
+    /// Parent room, if this room is a discussion.
+    pub fn parent(&self) -> Option<&str> {
+        self.details().prid.as_deref()
+    }
+
+    pub fn is_team_main(&self) -> bool {
+        self.details().team_main
+    }
+
+    pub fn last_message(&self) -> Option<RoomEventData> {
+        let raw = self.details().last_message.as_ref()?;
+        RoomEventData::deserialize(raw)
+            .map_err(|e| debug!("Could not decode last message {}: {}", raw, e))
+            .ok()
+    }
+
+    /// Timestamp of the last message seen in this room.
+    pub fn last_seen(&self) -> Option<Timestamp> {
+        match self {
+            Room::Chat{lm,..} => *lm,
+            Room::Direct{lm,..} => *lm,
+            Room::LiveChat{lm,..} => *lm,
+            Room::Private{lm,..} => *lm,
+        }
+    }
+
     fn lm(&mut self) -> &mut Option<Timestamp> {
         match self {
             Room::Chat{lm,..} => lm,
@@ -149,7 +298,73 @@ impl Room {
     }
 
 }
-//TODO proper timestamp serde
+
+/// A team groups rooms under a main room, whose members are the team members.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Team {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub name: String,
+    /// 0 for public teams, 1 for private ones.
+    #[serde(rename = "type")]
+    pub kind: u8,
+    /// The team's main room.
+    pub room_id: String,
+    #[serde(default)]
+    pub created_by: Option<ShortUser>,
+    #[serde(default)]
+    pub created_at: Option<Timestamp>,
+    #[serde(default)]
+    pub rooms: Option<u64>,
+    #[serde(default)]
+    pub number_of_users: Option<u64>,
+}
+
+impl Team {
+    pub fn is_private(&self) -> bool {
+        self.kind == 1
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TeamMember {
+    pub user: ShortUser,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// The user's membership in a room, with read state and notification counters.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscription {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub rid: String,
+    #[serde(rename = "t")]
+    pub room_type: char,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub fname: Option<String>,
+    #[serde(default)]
+    pub open: bool,
+    #[serde(default)]
+    pub alert: bool,
+    #[serde(default)]
+    pub unread: u64,
+    #[serde(default)]
+    pub user_mentions: u64,
+    #[serde(default)]
+    pub group_mentions: u64,
+    /// Last time the user read this room.
+    #[serde(default)]
+    pub ls: Option<Timestamp>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default, rename = "f")]
+    pub favorite: bool,
+}
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -158,12 +373,175 @@ pub struct RoomExtraInfo {
     pub room_participant: bool,
     pub room_type: char,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
+pub struct AttachmentField {
+    #[serde(default)]
+    pub short: bool,
+    pub title: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct Attachment {
+    #[serde(default, rename = "type")]
+    pub kind: Option<String>,
     #[serde(default)]
     pub title: Option<String>,
+    /// Relative download link for uploaded files, see `rest::Client::download`.
+    #[serde(default)]
+    pub title_link: Option<String>,
+    #[serde(default)]
+    pub title_link_download: bool,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub text: Option<String>,
     #[serde(default)]
     pub image_url: Option<String>,
+    #[serde(default)]
+    pub image_type: Option<String>,
+    #[serde(default)]
+    pub image_size: Option<u64>,
+    #[serde(default)]
+    pub audio_url: Option<String>,
+    #[serde(default)]
+    pub audio_type: Option<String>,
+    #[serde(default)]
+    pub audio_size: Option<u64>,
+    #[serde(default)]
+    pub video_url: Option<String>,
+    #[serde(default)]
+    pub video_type: Option<String>,
+    #[serde(default)]
+    pub video_size: Option<u64>,
+    #[serde(default)]
+    pub author_name: Option<String>,
+    #[serde(default)]
+    pub message_link: Option<String>,
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<AttachmentField>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+impl Attachment {
+    /// Size in bytes of the attached image, audio or video file, if known.
+    pub fn size(&self) -> Option<u64> {
+        self.image_size.or(self.audio_size).or(self.video_size)
+    }
+}
+
+#[derive(Deserialize)]
+struct RawMention {
+    #[serde(rename = "_id")]
+    id: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(from = "RawMention")]
+pub enum Mention {
+    All,
+    Here,
+    User { id: UserID, username: String, name: Option<String> },
+}
+
+impl From<RawMention> for Mention {
+    fn from(m: RawMention) -> Self {
+        match m.id.as_str() {
+            "all" => Mention::All,
+            "here" => Mention::Here,
+            _ => Mention::User {
+                username: m.username.unwrap_or_default(),
+                id: UserID(m.id),
+                name: m.name,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UrlPreview {
+    pub url: String,
+    #[serde(default)]
+    pub meta: Map<String, Value>,
+}
+
+impl UrlPreview {
+    fn meta(&self, keys: &[&str]) -> Option<&str> {
+        keys.iter().find_map(|k| self.meta.get(*k).and_then(Value::as_str))
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.meta(&["pageTitle", "ogTitle", "twitterTitle"])
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.meta(&["description", "ogDescription", "twitterDescription"])
+    }
+
+    pub fn image(&self) -> Option<&str> {
+        self.meta(&["ogImage", "twitterImage"])
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FileInfo {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub name: String,
+    #[serde(default, rename = "type")]
+    pub mime: Option<String>,
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Reaction {
+    #[serde(default)]
+    pub usernames: Vec<String>,
+}
+
+/// Reactions on a message, keyed by emoji shortcode (e.g. `:thumbsup:`).
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Reactions(pub BTreeMap<String, Reaction>);
+
+impl Reactions {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn usernames(&self, emoji: &str) -> &[String] {
+        self.0.get(emoji).map(|r| &r.usernames[..]).unwrap_or(&[])
+    }
+
+    pub fn count(&self, emoji: &str) -> usize {
+        self.usernames(emoji).len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &[String])> {
+        self.0.iter().map(|(emoji, r)| (emoji.as_str(), &r.usernames[..]))
+    }
+
+    /// (emoji, username) pairs present in `self` but not in `other`.
+    pub fn difference(&self, other: &Reactions) -> Vec<(String, String)> {
+        let mut diff = vec![];
+        for (emoji, users) in self.iter() {
+            let theirs = other.usernames(emoji);
+            for user in users {
+                if !theirs.contains(user) {
+                    diff.push((emoji.to_string(), user.clone()));
+                }
+            }
+        }
+        diff
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -176,17 +554,150 @@ pub struct RoomEventData {
     #[serde(default)]
     pub t: Option<String>,
     pub u: ShortUser,
+    /// Role granted or revoked, on `subscription-role-*` system messages.
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default, rename="editedAt")]
+    pub edited_at: Option<Timestamp>,
+    /// Last write to the message, whatever changed.
+    #[serde(default, rename="_updatedAt")]
+    pub updated_at: Option<Timestamp>,
+    #[serde(default, rename="editedBy")]
+    pub edited_by: Option<ShortUser>,
+    /// Thread this message is a reply to.
+    #[serde(default)]
+    pub tmid: Option<MessageID>,
+    /// Number of replies, on a thread's root message.
+    #[serde(default)]
+    pub tcount: Option<u64>,
+    /// Timestamp of the last reply, on a thread's root message.
+    #[serde(default)]
+    pub tlm: Option<Timestamp>,
+    #[serde(default)]
+    pub replies: Vec<UserID>,
+    /// Discussion started from this message.
+    #[serde(default)]
+    pub drid: Option<String>,
+    /// Number of messages in the discussion started from this message.
+    #[serde(default)]
+    pub dcount: Option<u64>,
+    /// Timestamp of the last message in the discussion started from this message.
+    #[serde(default)]
+    pub dlm: Option<Timestamp>,
     #[serde(default)]
     pub attachments: Vec<Attachment>,
-    #[serde(default, skip_serializing_if="serde_json::map::Map::is_empty")]
-    pub reactions: Map<String, Value>,
+    #[serde(default)]
+    pub file: Option<FileInfo>,
+    #[serde(default)]
+    pub files: Vec<FileInfo>,
+    #[serde(default, skip_serializing_if="Reactions::is_empty")]
+    pub reactions: Reactions,
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+    #[serde(default)]
+    pub channels: Vec<ShortRoom>,
+    #[serde(default)]
+    pub urls: Vec<UrlPreview>,
+    /// Parsed markdown, empty when the server did not send any (or sent one we
+    /// could not understand), in which case `msg` is all there is.
+    #[serde(default, deserialize_with="crate::markdown::lenient")]
+    pub md: Vec<Block>,
+}
+/// Decoded system message. Unless stated otherwise, the user who performed the
+/// action (or joined, left...) is the message author `u`; `user` fields hold
+/// the username of the user affected.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SystemMessage {
+    UserJoined,
+    UserLeft,
+    UserJoinedTeam,
+    UserLeftTeam,
+    UserAdded { user: String },
+    UserRemoved { user: String },
+    UserMuted { user: String },
+    UserUnmuted { user: String },
+    RoleAdded { user: String, role: String },
+    RoleRemoved { user: String, role: String },
+    RoomRenamed { name: String },
+    TopicChanged { topic: String },
+    DescriptionChanged { description: String },
+    AnnouncementChanged { announcement: String },
+    /// New room type, as sent by the server (`public`/`private`).
+    PrivacyChanged { room_type: String },
+    AvatarChanged,
+    RoomArchived,
+    RoomUnarchived,
+    MessageRemoved,
+    MessagePinned,
+    /// A discussion named `name` was started, in room `drid`.
+    DiscussionCreated { name: String, drid: Option<String> },
+    /// A system message type we do not decode.
+    Other(String),
+}
+
+impl RoomEventData {
+
+    pub fn system(&self) -> Option<SystemMessage> {
+        let msg = || self.msg.clone();
+        let role = || self.role.clone().unwrap_or_default();
+        Some(match self.t.as_deref()? {
+            "uj" => SystemMessage::UserJoined,
+            "ul" => SystemMessage::UserLeft,
+            "ujt" => SystemMessage::UserJoinedTeam,
+            "ult" => SystemMessage::UserLeftTeam,
+            "au" => SystemMessage::UserAdded { user: msg() },
+            "ru" => SystemMessage::UserRemoved { user: msg() },
+            "user-muted" => SystemMessage::UserMuted { user: msg() },
+            "user-unmuted" => SystemMessage::UserUnmuted { user: msg() },
+            "subscription-role-added" => SystemMessage::RoleAdded { user: msg(), role: role() },
+            "subscription-role-removed" => SystemMessage::RoleRemoved { user: msg(), role: role() },
+            "r" => SystemMessage::RoomRenamed { name: msg() },
+            "room_changed_topic" => SystemMessage::TopicChanged { topic: msg() },
+            "room_changed_description" => SystemMessage::DescriptionChanged { description: msg() },
+            "room_changed_announcement" => SystemMessage::AnnouncementChanged { announcement: msg() },
+            "room_changed_privacy" => SystemMessage::PrivacyChanged { room_type: msg() },
+            "room_changed_avatar" => SystemMessage::AvatarChanged,
+            "room-archived" => SystemMessage::RoomArchived,
+            "room-unarchived" => SystemMessage::RoomUnarchived,
+            "rm" => SystemMessage::MessageRemoved,
+            "message_pinned" => SystemMessage::MessagePinned,
+            "discussion-created" => SystemMessage::DiscussionCreated { name: msg(), drid: self.drid.clone() },
+            other => SystemMessage::Other(other.to_string()),
+        })
+    }
+
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct History {
+    pub messages: Vec<RoomEventData>,
+    #[serde(default)]
+    pub unread_not_loaded: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeletedMessage {
+    #[serde(rename="_id")]
+    pub id: MessageID,
+    #[serde(default, rename="_deletedAt")]
+    pub deleted_at: Option<Timestamp>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageSync {
+    #[serde(default)]
+    pub updated: Vec<RoomEventData>,
+    #[serde(default)]
+    pub deleted: Vec<DeletedMessage>,
 }
+
 #[derive(Debug, Deserialize)]
 pub struct RoomEvent {
     pub args: (RoomEventData ,RoomExtraInfo)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct ShortRoom {
     #[serde(rename="_id")] pub id: String,
     pub name: String,
@@ -213,12 +724,21 @@ mod tests {
         let room: Room = serde_json::from_str(r#"{"_id":"GENERAL","t":"c","name":"general","usernames":[],"usersCount":36,"default":true,"_updatedAt":{"$date":1618846200120},"lm":{"$date":1618836010099},"lastMessage":{"_id":"hCJtirxRuGAgN5Bom","rid":"GENERAL","msg":"Nice :moneybag:","ts":{"$date":1618836010099},"u":{"_id":"sSKs766qYEiKF8tss","username":"qwerty","name":"qwerty"},"_updatedAt":{"$date":1618836010128},"mentions":[],"channels":[]}}"#)
             .unwrap();
 
-        assert_eq!(room,
-            Room::Chat {id: "GENERAL".to_string(), 
-                        name: "general".to_string(), 
-                        topic: None,
-                        muted: vec![],
-                        lm: None });
+        match &room {
+            Room::Chat { id, name, topic, muted, lm, details } => {
+                assert_eq!(id, "GENERAL");
+                assert_eq!(name, "general");
+                assert_eq!(topic, &None);
+                assert!(muted.is_empty());
+                assert_eq!(lm, &Some(Timestamp::from_millis(1618836010099)));
+                assert_eq!(details.users_count, 36);
+                assert!(details.default);
+                assert_eq!(details.updated_at, Some(Timestamp::from_millis(1618846200120)));
+                assert_eq!(details.extra.get("usernames"), Some(&Value::Array(vec![])));
+            },
+            other => panic!("unexpected room {:?}", other),
+        }
+        assert_eq!(room.last_message().unwrap().msg, "Nice :moneybag:");
     }
 
     #[test]
@@ -257,5 +777,55 @@ mod tests {
         serde_json::from_str::<RoomEvent>(source).unwrap();
     }
 
+    #[test]
+    fn decode_system_messages() {
+        let data: RoomEventData = serde_json::from_str(r#"{
+            "_id": "aBYbfSa9XQDXgxNcm",
+            "rid": "fjGcXmddo5h8sp85n",
+            "msg": "qwerty",
+            "t": "subscription-role-added",
+            "role": "moderator",
+            "ts": {"$date": 1618995166553},
+            "u": {"_id": "hza29JX8SbnwqJwwh", "username": "syn", "name": "syn"}
+        }"#).unwrap();
+
+        assert_eq!(data.system(), Some(SystemMessage::RoleAdded { user: "qwerty".into(), role: "moderator".into() }));
+    }
+
+    #[test]
+    fn deserialize_mentions() {
+        let data: RoomEventData = serde_json::from_str(r#"{
+            "_id": "hCJtirxRuGAgN5Bom",
+            "rid": "GENERAL",
+            "msg": "@all @qwerty see #general",
+            "ts": {"$date": 1618836010099},
+            "u": {"_id": "sSKs766qYEiKF8tss", "username": "syn", "name": "syn"},
+            "mentions": [{"_id": "all", "username": "all"}, {"_id": "sSKs766qYEiKF8tss", "username": "qwerty", "name": "qwerty"}],
+            "channels": [{"_id": "GENERAL", "name": "general"}]
+        }"#).unwrap();
+
+        assert_eq!(data.mentions[0], Mention::All);
+        assert!(matches!(&data.mentions[1], Mention::User { username, .. } if username == "qwerty"));
+        assert_eq!(data.channels[0].name, "general");
+        assert!(data.md.is_empty());
+    }
+
+    #[test]
+    fn deserialize_team() {
+        let team: Team = serde_json::from_str(r#"{
+            "_id": "6093e4a9a7b1a20d3d5b1e36",
+            "name": "engineering",
+            "type": 1,
+            "roomId": "n4ZTgRwPmZZZXMFE5",
+            "createdAt": "2021-05-06T12:42:49.154Z",
+            "createdBy": {"_id": "hza29JX8SbnwqJwwh", "username": "syn"},
+            "_updatedAt": "2021-05-06T12:42:49.154Z"
+        }"#).unwrap();
+
+        assert!(team.is_private());
+        assert_eq!(team.room_id, "n4ZTgRwPmZZZXMFE5");
+        assert_eq!(team.created_at, Timestamp::parse_iso8601("2021-05-06T12:42:49.154Z"));
+    }
+
 }
 
@@ -1,11 +1,42 @@
 use std::collections::HashMap;
-use crate::{Handle, Rasta, schema::Room};
-use anyhow::{Result, anyhow};
+use crate::{Error, Event, Handle, Rasta, Result};
+use crate::event::{Change, PresenceChanged};
+use crate::schema::{Room, Status, Subscription, UserID};
+
+/// Last known status of every user we have heard about.
+#[derive(Debug, Clone, Default)]
+pub struct PresenceCache {
+    users: HashMap<UserID, (String, Status)>,
+    usernames: HashMap<String, UserID>,
+}
+
+impl PresenceCache {
+
+    pub fn update(&mut self, change: &PresenceChanged) {
+        self.usernames.insert(change.username.clone(), change.user.clone());
+        self.users.insert(change.user.clone(), (change.username.clone(), change.status()));
+    }
+
+    pub fn get(&self, user: &UserID) -> Option<&Status> {
+        self.users.get(user).map(|(_, status)| status)
+    }
+
+    pub fn by_username(&self, username: &str) -> Option<&Status> {
+        self.usernames.get(username).and_then(|id| self.get(id))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&UserID, &str, &Status)> {
+        self.users.iter().map(|(id, (name, status))| (id, name.as_str(), status))
+    }
+
+}
 
 #[derive(Debug, Clone)]
 pub struct Session {
     rooms: Vec<Room>,
     directs: HashMap<String,String>,
+    subscriptions: HashMap<String,Subscription>,
+    presence: PresenceCache,
 }
 
 
@@ -15,8 +46,83 @@ impl Session {
         &self.rooms
     }
 
+    pub fn new(rooms: Vec<Room>, subscriptions: Vec<Subscription>) -> Self {
+        let subscriptions = subscriptions.into_iter()
+            .map(|sub| (sub.rid.clone(), sub))
+            .collect();
+        Self { rooms, directs: HashMap::new(), subscriptions, presence: PresenceCache::default() }
+    }
+
     pub async fn from(client: &mut Rasta) -> Result<Self> {
-        Ok(Self{ rooms: client.rooms().await?, directs: HashMap::new()})
+        let subscriptions = client.room_subscriptions().await?;
+        Ok(Self::new(client.rooms().await?, subscriptions))
+    }
+
+    pub fn subscription(&self, room_id: &str) -> Option<&Subscription> {
+        self.subscriptions.get(room_id)
+    }
+
+    /// Subscriptions with unread messages or pending alerts.
+    pub fn unread(&self) -> impl Iterator<Item=&Subscription> {
+        self.subscriptions.values().filter(|sub| sub.unread > 0 || sub.alert)
+    }
+
+    /// Main rooms of the teams we are in.
+    pub fn teams(&self) -> impl Iterator<Item=&Room> {
+        self.rooms.iter().filter(|room| room.is_team_main())
+    }
+
+    /// Known rooms of a team, its main room included.
+    pub fn team_rooms<'a>(&'a self, team_id: &'a str) -> impl Iterator<Item=&'a Room> + 'a {
+        self.rooms.iter().filter(move |room| room.details().team_id.as_deref() == Some(team_id))
+    }
+
+    /// Known discussions started from a room.
+    pub fn discussions<'a>(&'a self, room_id: &'a str) -> impl Iterator<Item=&'a Room> + 'a {
+        self.rooms.iter().filter(move |room| room.parent() == Some(room_id))
+    }
+
+    /// The room a discussion was started from, if we know it.
+    pub fn parent_room(&self, room: &Room) -> Option<&Room> {
+        let prid = room.parent()?;
+        self.rooms.iter().find(|r| r.id() == prid)
+    }
+
+    pub fn presence(&self) -> &PresenceCache {
+        &self.presence
+    }
+
+    /// Keeps the session up to date with an event received from the server.
+    pub fn apply(&mut self, event: &Event) {
+        match event {
+            Event::PresenceChanged(change) => self.presence.update(change),
+            Event::RoomChanged(Change::Inserted(room)) | Event::RoomChanged(Change::Updated(room)) => {
+                match self.room_by_id(room.id()) {
+                    Some(known) => *known = room.clone(),
+                    None => self.rooms.push(room.clone()),
+                }
+            },
+            Event::RoomChanged(Change::Removed(id)) => self.remove_room(id),
+            Event::SubscriptionChanged(Change::Inserted(sub)) | Event::SubscriptionChanged(Change::Updated(sub)) => {
+                self.subscriptions.insert(sub.rid.clone(), sub.clone());
+            },
+            Event::SubscriptionChanged(Change::Removed(id)) => {
+                // We left or were kicked from the room
+                let rid = self.subscriptions.values()
+                    .find(|sub| &sub.id == id)
+                    .map(|sub| sub.rid.clone());
+                if let Some(rid) = rid {
+                    self.subscriptions.remove(&rid);
+                    self.remove_room(&rid);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn remove_room(&mut self, id: &str) {
+        self.rooms.retain(|room| room.id() != id);
+        self.directs.retain(|_, rid| rid != id);
     }
 
     pub fn room_by_id(&mut self, id: &str) -> Option<&mut Room> {
@@ -47,7 +153,7 @@ impl Session {
 
     pub async fn direct_room(&mut self, handle: &mut Handle, user: &str) -> Result<&mut Room> {
         if let Some(id) = self.directs.get(user).cloned() {
-            self.room_by_id(&id).ok_or(anyhow!(""))     
+            self.room_by_id(&id).ok_or_else(|| Error::NotFound(user.to_string()))     
         } else {
             let room = handle.create_direct(user.to_string()).await?;
             self.directs.insert(user.into(), room.id().into());
@@ -63,4 +169,57 @@ impl Session {
         } 
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    fn room(id: &str, topic: &str) -> Room {
+        serde_json::from_value(json!({ "_id": id, "t": "c", "name": id, "topic": topic })).unwrap()
+    }
+
+    fn subscription(id: &str, rid: &str, unread: u64) -> Subscription {
+        serde_json::from_value(json!({ "_id": id, "rid": rid, "t": "c", "unread": unread })).unwrap()
+    }
+
+    fn topic(session: &mut Session, id: &str) -> Option<String> {
+        match session.room_by_id(id)? {
+            Room::Chat { topic, .. } => topic.clone(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn apply_room_changes() {
+        let mut session = Session::new(vec![room("general", "hello")], vec![]);
+
+        session.apply(&Event::RoomChanged(Change::Inserted(room("random", "whatever"))));
+        assert_eq!(session.rooms().len(), 2);
+
+        session.apply(&Event::RoomChanged(Change::Updated(room("general", "goodbye"))));
+        assert_eq!(session.rooms().len(), 2);
+        assert_eq!(topic(&mut session, "general").as_deref(), Some("goodbye"));
+
+        session.apply(&Event::RoomChanged(Change::Removed("random".into())));
+        assert_eq!(session.rooms().len(), 1);
+        assert!(session.room_by_id("random").is_none());
+    }
+
+    #[test]
+    fn apply_subscription_changes() {
+        let mut session = Session::new(vec![room("general", "hello")], vec![subscription("sub1", "general", 0)]);
+
+        session.apply(&Event::SubscriptionChanged(Change::Updated(subscription("sub1", "general", 3))));
+        assert_eq!(session.subscription("general").map(|s| s.unread), Some(3));
+        assert_eq!(session.unread().count(), 1);
+
+        // Removals only carry the subscription _id, not the room id
+        session.apply(&Event::SubscriptionChanged(Change::Removed("sub1".into())));
+        assert!(session.subscription("general").is_none());
+        assert!(session.rooms().is_empty());
+    }
+
+}
@@ -0,0 +1,255 @@
+use std::{fmt, time::{Duration, SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de, ser::SerializeMap};
+
+/// A point in time with millisecond precision, as used throughout Rocket.Chat.
+///
+/// The realtime API encodes those as EJSON (`{"$date": 1618836010099}`) and the REST
+/// API as ISO-8601 strings (`"2021-04-19T12:40:10.099Z"`); both are accepted when
+/// deserializing, and the EJSON form is produced when serializing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Timestamp(i64);
+
+const MS_PER_DAY: i64 = 86_400_000;
+
+impl Timestamp {
+
+    pub fn from_millis(ms: i64) -> Self {
+        Timestamp(ms)
+    }
+
+    /// Milliseconds since the Unix epoch.
+    pub fn as_millis(&self) -> i64 {
+        self.0
+    }
+
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    pub fn parse_iso8601(s: &str) -> Option<Self> {
+        let (date, time) = s.split_once('T').or_else(|| s.split_once(' '))?;
+
+        let mut date = date.splitn(3, '-');
+        let year: i64 = date.next()?.parse().ok()?;
+        let month: u32 = date.next()?.parse().ok()?;
+        let day: u32 = date.next()?.parse().ok()?;
+
+        let (time, offset) = if let Some(time) = time.strip_suffix('Z') {
+            (time, 0)
+        } else if let Some(pos) = time.rfind(|c| c == '+' || c == '-') {
+            let (time, offset) = time.split_at(pos);
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let offset = offset[1..].replace(':', "");
+            if offset.len() != 4 || !offset.bytes().all(|b| b.is_ascii_digit()) { return None }
+            let hours: i64 = offset[..2].parse().ok()?;
+            let minutes: i64 = offset[2..].parse().ok()?;
+            (time, sign * (hours * 60 + minutes))
+        } else {
+            (time, 0)
+        };
+
+        let (hms, frac) = time.split_once('.').unwrap_or((time, ""));
+        let mut hms = hms.splitn(3, ':');
+        let hour: u32 = hms.next()?.parse().ok()?;
+        let minute: u32 = hms.next()?.parse().ok()?;
+        let second: u32 = hms.next()?.parse().ok()?;
+
+        if !frac.bytes().all(|b| b.is_ascii_digit()) { return None }
+        let millis: i64 = format!("{:0<3}", &frac[..frac.len().min(3)]).parse().ok()?;
+
+        // Four digit years keep the arithmetic below clear of overflows
+        if year > 9999 || !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month)
+            || hour > 23 || minute > 59 || second > 60 {
+            return None
+        }
+
+        let days = days_from_civil(year, month, day);
+        let seconds = (hour * 3600 + minute * 60 + second) as i64 - offset * 60;
+        Some(Timestamp(days * MS_PER_DAY + seconds * 1000 + millis))
+    }
+
+    /// Formats as `2021-04-19T12:40:10.099Z`, the form the REST API expects.
+    pub fn to_iso8601(&self) -> String {
+        let days = self.0.div_euclid(MS_PER_DAY);
+        let ms = self.0.rem_euclid(MS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                year, month, day,
+                ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+    }
+
+}
+
+// Calendar conversions from http://howardhinnant.github.io/date_algorithms.html
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_iso8601())
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(t: SystemTime) -> Self {
+        match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => Timestamp(d.as_millis() as i64),
+            Err(e) => Timestamp(-(e.duration().as_millis() as i64)),
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(ts: Timestamp) -> Self {
+        if ts.0 >= 0 {
+            UNIX_EPOCH + Duration::from_millis(ts.0 as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_millis(ts.0.unsigned_abs())
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<Timestamp> for chrono::DateTime<chrono::Utc> {
+    fn from(ts: Timestamp) -> Self {
+        SystemTime::from(ts).into()
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> From<chrono::DateTime<Tz>> for Timestamp {
+    fn from(t: chrono::DateTime<Tz>) -> Self {
+        Timestamp(t.timestamp_millis())
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<Timestamp> for time::OffsetDateTime {
+    fn from(ts: Timestamp) -> Self {
+        SystemTime::from(ts).into()
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<time::OffsetDateTime> for Timestamp {
+    fn from(t: time::OffsetDateTime) -> Self {
+        SystemTime::from(t).into()
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry("$date", &self.0)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+struct TimestampVisitor;
+
+impl<'de> de::Visitor<'de> for TimestampVisitor {
+    type Value = Timestamp;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an EJSON date, an ISO-8601 string or milliseconds since the epoch")
+    }
+
+    fn visit_i64<E: de::Error>(self, ms: i64) -> Result<Timestamp, E> {
+        Ok(Timestamp(ms))
+    }
+
+    fn visit_u64<E: de::Error>(self, ms: u64) -> Result<Timestamp, E> {
+        Ok(Timestamp(ms as i64))
+    }
+
+    fn visit_f64<E: de::Error>(self, ms: f64) -> Result<Timestamp, E> {
+        Ok(Timestamp(ms as i64))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Timestamp, E> {
+        Timestamp::parse_iso8601(s)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Str(s), &self))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Timestamp, A::Error> {
+        let mut date = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "$date" {
+                date = Some(map.next_value::<i64>()?);
+            } else {
+                map.next_value::<de::IgnoredAny>()?;
+            }
+        }
+        date.map(Timestamp).ok_or_else(|| de::Error::missing_field("$date"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn iso8601_round_trip() {
+        let ts = Timestamp::parse_iso8601("2021-04-19T12:40:10.099Z").unwrap();
+        assert_eq!(ts, Timestamp::from_millis(1618836010099));
+        assert_eq!(ts.to_iso8601(), "2021-04-19T12:40:10.099Z");
+        assert_eq!(Timestamp::parse_iso8601("2021-04-19T14:40:10.099+02:00"), Some(ts));
+        assert_eq!(Timestamp::from_millis(0).to_iso8601(), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn iso8601_rejects_garbage() {
+        assert_eq!(Timestamp::parse_iso8601("2021-04-19T12:40:10.099+0é0"), None);
+        assert_eq!(Timestamp::parse_iso8601("9223372036854775807-01-01T00:00:00Z"), None);
+        assert_eq!(Timestamp::parse_iso8601("2021-04-19T12:40:10.09x9Z"), None);
+        assert_eq!(Timestamp::parse_iso8601("2021-04-19T12:-5:10Z"), None);
+        assert_eq!(Timestamp::parse_iso8601("2021-02-31T12:40:10Z"), None);
+        assert_eq!(Timestamp::parse_iso8601("2021-02-29T12:40:10Z"), None);
+        assert!(Timestamp::parse_iso8601("2020-02-29T12:40:10Z").is_some());
+    }
+
+    #[test]
+    fn serde_both_forms() {
+        let ejson: Timestamp = serde_json::from_str(r#"{"$date": 1618836010099}"#).unwrap();
+        let iso: Timestamp = serde_json::from_str(r#""2021-04-19T12:40:10.099Z""#).unwrap();
+        assert_eq!(ejson, iso);
+        assert_eq!(serde_json::to_string(&iso).unwrap(), r#"{"$date":1618836010099}"#);
+    }
+
+}